@@ -0,0 +1,129 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::{Child, ChildStdout, Command, Stdio},
+};
+
+use color_eyre::{
+    Result,
+    eyre::{WrapErr, bail, eyre},
+};
+use log::info;
+use tempfile::TempDir;
+
+use crate::cli::ClassifyArgs;
+
+/// A Kraken2 process started by `classify-and-extract`.
+pub struct Kraken2Run {
+    child: Child,
+    command: Vec<String>,
+    report: PathBuf,
+    // set when the output is written to a file rather than read from the process as it runs
+    output: Option<PathBuf>,
+    // holds the report, and any output written to a file, unless the report is kept
+    _temp_dir: TempDir,
+}
+
+impl Kraken2Run {
+    /// Starts Kraken2 on the input files. With `stream_output` its output is piped to kractor, otherwise it is
+    /// written to a temporary file to be read once Kraken2 has finished.
+    pub fn spawn(args: &ClassifyArgs, stream_output: bool) -> Result<Self> {
+        let temp_dir = tempfile::Builder::new()
+            .prefix("kractor-kraken2-")
+            .tempdir()
+            .wrap_err("Failed to create a temporary directory for the Kraken2 output")?;
+        let report = args
+            .kraken2_report
+            .clone()
+            .unwrap_or_else(|| temp_dir.path().join("kraken2_report.txt"));
+        let output = (!stream_output).then(|| temp_dir.path().join("kraken2_output.txt"));
+
+        let mut kraken2_args: Vec<OsString> = vec![
+            "--db".into(),
+            args.db.clone().into(),
+            "--threads".into(),
+            args.kraken2_threads.to_string().into(),
+        ];
+        if args.extract.interleaved {
+            kraken2_args.push("--interleaved".into());
+        } else if args.extract.input.len() == 2 {
+            kraken2_args.push("--paired".into());
+        }
+        kraken2_args.extend(args.kraken2_args.iter().map(OsString::from));
+        kraken2_args.extend([
+            "--output".into(),
+            output.as_deref().unwrap_or(Path::new("-")).into(),
+            "--report".into(),
+            report.clone().into(),
+        ]);
+        kraken2_args.extend(args.extract.input.iter().map(OsString::from));
+
+        let command: Vec<String> = std::iter::once(args.kraken2.as_os_str())
+            .chain(kraken2_args.iter().map(OsString::as_os_str))
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        info!("Running Kraken2: {}", command.join(" "));
+
+        let child = Command::new(&args.kraken2)
+            .args(&kraken2_args)
+            .stdin(Stdio::null())
+            .stdout(if stream_output {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .spawn()
+            .wrap_err_with(|| format!("Failed to run Kraken2: {}", args.kraken2.display()))?;
+
+        Ok(Self {
+            child,
+            command,
+            report,
+            output,
+            _temp_dir: temp_dir,
+        })
+    }
+
+    /// The Kraken2 standard output, when it is streamed.
+    pub fn take_stdout(&mut self) -> Option<ChildStdout> {
+        self.child.stdout.take()
+    }
+
+    /// Waits for Kraken2 to write its output to a file, returning the file path.
+    pub fn wait_for_output(&mut self) -> Result<PathBuf> {
+        let output = self
+            .output
+            .clone()
+            .ok_or_else(|| eyre!("The Kraken2 output is streamed, not written to a file"))?;
+        let status = self.child.wait().wrap_err("Failed to wait for Kraken2")?;
+        if !status.success() {
+            bail!("Kraken2 failed ({status})");
+        }
+        Ok(output)
+    }
+
+    pub fn report(&self) -> &Path {
+        &self.report
+    }
+
+    pub fn command(&self) -> &[String] {
+        &self.command
+    }
+
+    /// Waits for Kraken2 to exit after the extraction, returning an error if either failed. A Kraken2 failure is
+    /// reported first, as it usually causes the extraction to fail too.
+    pub fn finish<T>(mut self, result: Result<T>) -> Result<T> {
+        // closing the pipe stops Kraken2 if the extraction failed before reading all of its output
+        drop(self.child.stdout.take());
+        let status = self.child.wait().wrap_err("Failed to wait for Kraken2")?;
+        if status.success() {
+            return result;
+        }
+        match result {
+            // Kraken2 was stopped by a signal, most likely the pipe closed after the extraction failed
+            Err(err) if status.code().is_none() => Err(err),
+            Err(err) => Err(err.wrap_err(format!("Kraken2 failed ({status})"))),
+            Ok(_) => Err(eyre!("Kraken2 failed ({status})")),
+        }
+    }
+}
@@ -1,6 +1,8 @@
-use std::path::PathBuf;
+use std::{num::NonZeroUsize, path::PathBuf};
 
-use clap::{Parser, ValueEnum};
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum, builder::ArgPredicate};
+
+use crate::parsers::kraken::{ParentLimit, Rank};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
 pub enum OutputFormat {
@@ -13,30 +15,118 @@ pub enum OutputFormat {
 #[command(
     version,
     about = "Extract reads from a FASTQ or FASTA file based on taxonomic classification via Kraken2.",
-    author = "Sam Sims"
+    author = "Sam Sims",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    group(
+        ArgGroup::new("report_options")
+            .args([
+                "confidence",
+                "min_rank",
+                "max_rank",
+                "taxon_name",
+                "parents_up_to",
+                "clade_rank"
+            ])
+            .multiple(true)
+            .requires("report")
+    )
 )]
 pub struct Cli {
-    /// Input file path(s). Accepts up to 2 files (for paired-end reads).
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Kraken2 stdout file path, optionally compressed (gz, bz2). Use - to read from stdin.
+    #[arg(short = 'k', long = "kraken", required = true)]
+    pub kraken: Option<PathBuf>,
+    /// Kraken2 report file path, optionally compressed (gz, bz2).
+    #[arg(short = 'r', long = "report", required_if_eq_any([("parents", "true"), ("children", "true")]))]
+    pub report: Option<PathBuf>,
+    #[command(flatten)]
+    pub extract: ExtractArgs,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run Kraken2 on the input and extract reads from its output as it is written.
+    ClassifyAndExtract(ClassifyArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct ClassifyArgs {
+    /// Kraken2 database directory.
+    #[arg(long = "db", required = true)]
+    pub db: PathBuf,
+    /// Kraken2 executable to run.
+    #[arg(long = "kraken2", default_value = "kraken2")]
+    pub kraken2: PathBuf,
+    /// Number of threads used by Kraken2.
+    #[arg(long = "kraken2-threads", default_value = "1")]
+    pub kraken2_threads: NonZeroUsize,
+    /// Further options passed to Kraken2 as they are, given after -- (e.g. -- --confidence 0.1 --gzip-compressed).
+    #[arg(last = true, value_name = "KRAKEN2_ARGS")]
+    pub kraken2_args: Vec<String>,
+    /// Keep the Kraken2 report at this path, rather than in a temporary directory.
+    #[arg(long = "kraken2-report")]
+    pub kraken2_report: Option<PathBuf>,
+    #[command(flatten)]
+    pub extract: ExtractArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct ExtractArgs {
+    /// Input file path(s). Accepts up to 2 files (for paired-end reads). Use - to read from stdin.
     #[arg(short = 'i', long = "input", num_args(1..=2), required = true)]
     pub input: Vec<PathBuf>,
-    /// Output file path(s). Accepts up to 2 files (for paired-end reads).
+    /// Output file path(s). Accepts up to 2 files (for paired-end reads). Use - to write to stdout.
     #[arg(short = 'o', long = "output", num_args(1..=2), required = true)]
     pub output: Vec<PathBuf>,
-    /// Kraken2 stdout file path.
-    #[arg(short = 'k', long = "kraken", required = true)]
-    pub kraken: PathBuf,
-    /// Kraken2 report file path.
-    #[arg(short = 'r', long = "report", required_if_eq_any([("parents", "true"), ("children", "true")]))]
-    pub report: Option<PathBuf>,
     /// One or more taxon IDs to extract reads for.
-    #[arg(short = 't', long = "taxid", required = true, num_args(1..))]
+    #[arg(short = 't', long = "taxid", required_unless_present = "taxon_name", num_args(1..))]
     pub taxid: Vec<i32>,
+    /// Scientific name of a taxon to extract reads for, as written in the Kraken2 report (e.g. "Escherichia coli"). Can be given more than once. Requires a Kraken2 report file.
+    #[arg(long = "taxon-name")]
+    pub taxon_name: Vec<String>,
+    /// Match --taxon-name regardless of case.
+    #[arg(long = "ignore-case", requires = "taxon_name")]
+    pub ignore_case: bool,
+    /// Treat --taxon-name as a regular expression, selecting every taxon whose name it matches.
+    #[arg(long = "name-regex", requires = "taxon_name")]
+    pub name_regex: bool,
     /// Include all parent taxon IDs in the output. Requires a Kraken2 report file.
-    #[arg(short = 'p', long)]
+    #[arg(
+        short = 'p',
+        long,
+        default_value_if("parents_up_to", ArgPredicate::IsPresent, "true")
+    )]
     pub parents: bool,
-    /// Include all child taxon IDs in the output. Requires a Kraken2 report file.
+    /// Stop including parents at the ancestor of this rank (e.g. F) or this ancestor taxon ID, rather than at the root. Implies --parents.
+    #[arg(long = "parents-up-to", value_name = "RANK|TAXID")]
+    pub parents_up_to: Option<ParentLimit>,
+    /// Extract the whole clade at this rank around each taxon ID (e.g. G for the genus of a species), walking up to its ancestor at the rank and including all of that ancestor's children. Requires a Kraken2 report file.
+    #[arg(long = "clade-rank", value_name = "RANK")]
+    pub clade_rank: Option<Rank>,
+    /// Include all child taxon IDs in the output. Combined with --parents, both are included. Requires a Kraken2 report file.
     #[arg(short = 'c', long)]
     pub children: bool,
+    /// Treat the input as interleaved paired-end reads, each read followed by its mate. Pairs are kept or dropped together, and written to two outputs or interleaved to one.
+    #[arg(long)]
+    pub interleaved: bool,
+    /// Instead of failing when the mates of paired input files fall out of step, pair them up by read ID and skip the reads left without a mate.
+    #[arg(
+        long = "allow-orphans",
+        conflicts_with = "ordered",
+        default_value_if("orphan_output", ArgPredicate::IsPresent, "true")
+    )]
+    pub allow_orphans: bool,
+    /// Write the selected reads left without a mate to this file. Implies --allow-orphans.
+    #[arg(long = "orphan-output", conflicts_with = "ordered")]
+    pub orphan_output: Option<PathBuf>,
+    /// Keep only the taxa added by --children, --parents or --clade-rank at this rank or below it, as a Kraken2 report rank code (e.g. S keeps species and strains). Requires a Kraken2 report file.
+    #[arg(long = "min-rank")]
+    pub min_rank: Option<Rank>,
+    /// Keep only the taxa added by --children, --parents or --clade-rank at this rank or above it (e.g. G stops at genus, leaving out G1 and species). Requires a Kraken2 report file.
+    #[arg(long = "max-rank")]
+    pub max_rank: Option<Rank>,
     /// Compression format for output files (gz, bz2). Overrides the inferred format.
     #[arg(long = "compression-format", value_parser(validate_compression))]
     pub output_type: Option<niffler::Format>,
@@ -50,12 +140,49 @@ pub struct Cli {
     /// Exclude specified taxon IDs from the output.
     #[arg(long)]
     pub exclude: bool,
+    /// Recompute each read's confidence score from its LCA k-mer map and require at least this score (0-1). Requires a Kraken2 report file.
+    #[arg(long, value_parser(validate_fraction))]
+    pub confidence: Option<f64>,
+    /// Reassign reads failing --confidence to the first ancestor that passes, as Kraken2 does, instead of treating them as unclassified.
+    #[arg(long, requires = "confidence")]
+    pub reassign: bool,
+    /// Require at least this fraction (0-1) of a read's non-ambiguous k-mers to be assigned within the selected taxa.
+    #[arg(
+        long = "min-clade-fraction",
+        conflicts_with = "exclude",
+        value_parser(validate_fraction)
+    )]
+    pub min_clade_fraction: Option<f64>,
+    /// With --exclude, also drop reads with at least this many k-mers assigned within the excluded taxa, whatever their final call.
+    #[arg(long = "strict-exclude-kmers", requires = "exclude", value_parser = clap::value_parser!(u32).range(1..))]
+    pub strict_exclude_kmers: Option<u32>,
+    /// With --exclude, also drop reads with a run of at least this many consecutive k-mers assigned within the excluded taxa.
+    #[arg(long = "strict-exclude-run", requires = "exclude", value_parser = clap::value_parser!(u32).range(1..))]
+    pub strict_exclude_run: Option<u32>,
+    /// Skip reads where more than this fraction (0-1) of their k-mers are ambiguous.
+    #[arg(long = "max-ambiguous-fraction", value_parser(validate_fraction))]
+    pub max_ambiguous_fraction: Option<f64>,
+    /// Apply the k-mer filters to each mate of paired-end Kraken2 output, rather than to the pair as a whole.
+    #[arg(long = "per-mate")]
+    pub per_mate: bool,
+    /// Number of threads used to parse the Kraken2 output.
+    #[arg(long, default_value = "1")]
+    pub threads: NonZeroUsize,
+    /// Memory limit for the IDs of the reads to extract (e.g. 512M, 8G). Beyond it they are spilled to disk, and the input is read in several passes.
+    #[arg(long = "max-memory", value_parser(validate_memory_size))]
+    pub max_memory: Option<usize>,
+    /// Walk the Kraken2 output and the input together, as Kraken2 writes reads in input order, instead of collecting the IDs of the reads to extract. Uses constant memory, and fails if the read order differs.
+    #[arg(long, conflicts_with_all = ["threads", "max_memory"])]
+    pub ordered: bool,
     /// Output sequence format (auto, fasta, fastq).
     #[arg(long = "output-format", value_enum, default_value = "auto")]
     pub output_format: OutputFormat,
     /// Enable a JSON summary output written to stdout.
     #[arg(long = "summary")]
     pub summary: bool,
+    /// Write the JSON summary to this file instead of stdout. Implies --summary.
+    #[arg(long = "summary-file")]
+    pub summary_file: Option<PathBuf>,
     /// Disable detection and skipping of any header lines in the Kraken2 report.
     #[arg(long = "no-header-detect")]
     pub no_report_header_detect: bool,
@@ -64,6 +191,19 @@ pub struct Cli {
     pub verbose: bool,
 }
 
+impl ExtractArgs {
+    /// Whether the Kraken2 report is needed to choose the reads to extract.
+    pub fn needs_report(&self) -> bool {
+        self.parents
+            || self.children
+            || self.confidence.is_some()
+            || self.min_rank.is_some()
+            || self.max_rank.is_some()
+            || self.clade_rank.is_some()
+            || !self.taxon_name.is_empty()
+    }
+}
+
 fn validate_compression(s: &str) -> Result<niffler::Format, String> {
     match s {
         "gz" => Ok(niffler::Format::Gzip),
@@ -73,6 +213,31 @@ fn validate_compression(s: &str) -> Result<niffler::Format, String> {
     }
 }
 
+fn validate_fraction(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(value) if (0.0..=1.0).contains(&value) => Ok(value),
+        _ => Err(format!("Invalid value: {s} Try a value between 0 and 1")),
+    }
+}
+
+fn validate_memory_size(s: &str) -> Result<usize, String> {
+    let upper = s.trim().to_ascii_uppercase();
+    let number = upper.strip_suffix('B').unwrap_or(&upper);
+    let (number, exponent) = match number.char_indices().last() {
+        Some((index, 'K')) => (&number[..index], 1),
+        Some((index, 'M')) => (&number[..index], 2),
+        Some((index, 'G')) => (&number[..index], 3),
+        Some((index, 'T')) => (&number[..index], 4),
+        _ => (number, 0),
+    };
+    match number.parse::<f64>() {
+        Ok(value) if value > 0.0 => Ok((value * 1024f64.powi(exponent)) as usize),
+        _ => Err(format!(
+            "Invalid memory size: {s} Try a size such as 512M or 8G"
+        )),
+    }
+}
+
 fn validate_compression_level(s: &str) -> Result<niffler::Level, String> {
     match s.parse::<u32>() {
         Ok(1) => Ok(niffler::Level::One),
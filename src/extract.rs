@@ -2,29 +2,73 @@ use std::path::{Path, PathBuf};
 
 use color_eyre::{
     Result,
-    eyre::{WrapErr, bail, eyre},
+    eyre::{WrapErr, bail, ensure, eyre},
 };
-use crossbeam::channel;
-use fxhash::FxHashSet;
+use crossbeam::channel::{self, Sender};
+use fxhash::FxHashMap;
 use log::{debug, info, warn};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
 
 use crate::{
     cli::OutputFormat,
     parsers::{
         fastx::{
-            FastxFormat, FastxRecord, detect_fastx_format, parse_fastx, resolve_output_format,
+            FastxFormat, FastxInput, FastxRecord, MateSync, PairedCounts, parse_fastx,
+            parse_fastx_ordered, parse_interleaved_fastx, parse_interleaved_fastx_ordered,
+            parse_paired_fastx, parse_paired_fastx_ordered, resolve_output_format,
             write_output_fastx,
         },
         kraken::{
-            ProcessedKrakenTree, build_tree_from_kraken_report, extract_children, extract_parents,
+            OrderedKrakenOutput, ParentLimit, ProcessedKrakenTree, Rank, Tree,
+            build_tree_from_kraken_report, extract_children, extract_parents,
+            find_ancestor_at_rank,
         },
     },
+    read_ids::ReadSelection,
 };
 
+// records queued for each writer with --ordered, so a slow writer holds back the reader rather than the input being
+// buffered in memory
+const ORDERED_QUEUE_CAPACITY: usize = 1024;
+
 #[derive(Debug, Clone)]
 pub struct CollectedTaxonIds {
     pub found: Vec<i32>,
     pub missing: Vec<i32>,
+    /// The taxonomic tree built from the report, empty if no report was given.
+    pub nodes: Vec<Tree>,
+    /// How each found taxon came to be selected.
+    pub origins: FxHashMap<i32, TaxonOrigin>,
+    /// The taxon IDs each requested taxon name resolved to, in the order the names were given.
+    pub resolved_names: Vec<ResolvedTaxonName>,
+}
+
+/// Why a taxon was selected, in order of precedence when it was reached in more than one way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TaxonOrigin {
+    /// Given with `--taxid` or `--taxon-name`.
+    Requested,
+    /// Added by `--children`.
+    Descendant,
+    /// Added by `--parents`.
+    Ancestor,
+    /// Added by `--clade-rank`, within the clade of a requested taxon.
+    Clade,
+}
+
+fn add_taxon(origins: &mut FxHashMap<i32, TaxonOrigin>, taxid: i32, origin: TaxonOrigin) {
+    origins
+        .entry(taxid)
+        .and_modify(|existing| *existing = (*existing).min(origin))
+        .or_insert(origin);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedTaxonName {
+    pub name: String,
+    pub taxon_ids: Vec<i32>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -35,16 +79,23 @@ pub struct KractorResult {
     pub output_format: FastxFormat,
 }
 
+/// The reads of paired input left without a mate, and how many of them were written to the orphan output.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OrphanCounts {
+    pub reads: usize,
+    pub reads_output: usize,
+}
+
 pub fn process_single_end(
-    reads_to_save: &FxHashSet<Vec<u8>>,
+    reads_to_save: &ReadSelection,
     input: &[PathBuf],
     output: &[PathBuf],
     compression_type: Option<niffler::Format>,
     compression_level: niffler::Level,
     requested_output_format: OutputFormat,
 ) -> Result<KractorResult> {
-    let input_format = detect_fastx_format(&input[0])
-        .wrap_err_with(|| format!("Failed to detect input format: {}", input[0].display()))?;
+    let mut fastx_input = FastxInput::open(&input[0])?;
+    let input_format = fastx_input.format();
     let output_format = resolve_output_format(input_format, requested_output_format);
 
     let (total_reads_parsed, total_reads_output) =
@@ -52,7 +103,8 @@ pub fn process_single_end(
             let (tx, rx) = channel::unbounded::<FastxRecord>();
 
             let reader = scope.spawn(|| {
-                let result = parse_fastx(&input[0], reads_to_save, &tx).map(|(count, _)| count);
+                let result = reads_to_save
+                    .for_each_pass(|selection| parse_fastx(&mut fastx_input, selection, &tx));
                 drop(tx);
                 result
                     .wrap_err_with(|| format!("Failed to parse input file: {}", input[0].display()))
@@ -87,141 +139,491 @@ pub fn process_single_end(
     })
 }
 
+/// Extracts paired-end reads, reading both inputs in step so that the mates of each pair are kept or dropped together.
+/// With [`MateSync::AllowOrphans`] reads without a mate are counted, and written to the orphan output if given.
 pub fn process_paired_end(
-    reads_to_save: &FxHashSet<Vec<u8>>,
+    reads_to_save: &ReadSelection,
     input: &[PathBuf],
     output: &[PathBuf],
     compression_type: Option<niffler::Format>,
     compression_level: niffler::Level,
     requested_output_format: OutputFormat,
-) -> Result<(KractorResult, KractorResult)> {
-    let input_format1 = detect_fastx_format(&input[0]).wrap_err_with(|| {
-        format!(
-            "Failed to detect first input format: {}",
-            input[0].display()
-        )
-    })?;
-    let input_format2 = detect_fastx_format(&input[1]).wrap_err_with(|| {
-        format!(
-            "Failed to detect second input format: {}",
-            input[1].display()
-        )
-    })?;
-
-    if input_format1 == FastxFormat::Fasta || input_format2 == FastxFormat::Fasta {
-        bail!("Two input files are not supported for FASTA input");
-    }
-
+    mate_sync: MateSync<&Path>,
+) -> Result<(KractorResult, OrphanCounts)> {
+    let mut fastx_inputs = open_paired_inputs(input)?;
     let input_format = FastxFormat::Fastq;
     let output_format = resolve_output_format(input_format, requested_output_format);
 
-    let ((reads1, reads2), (out1, out2)) =
-        std::thread::scope(|scope| -> Result<((usize, usize), (usize, usize))> {
-            let (tx1, rx1) = channel::unbounded::<FastxRecord>();
-            let (tx2, rx2) = channel::unbounded::<FastxRecord>();
+    let (counts, reads_output, orphans_output) =
+        std::thread::scope(|scope| -> Result<(PairedCounts, usize, usize)> {
+            let (orphan_tx, orphan_writer) = match mate_sync {
+                MateSync::AllowOrphans(Some(orphan_output)) => {
+                    let (tx, rx) = channel::unbounded::<FastxRecord>();
+                    let writer = scope.spawn(move || {
+                        write_output_fastx(
+                            rx,
+                            orphan_output,
+                            output_format,
+                            compression_type,
+                            compression_level,
+                        )
+                        .wrap_err_with(|| {
+                            format!(
+                                "Failed to write orphan output file: {}",
+                                orphan_output.display()
+                            )
+                        })
+                    });
+                    (Some(tx), Some(writer))
+                }
+                _ => (None, None),
+            };
 
-            let reader1 = scope.spawn(|| {
-                let result = parse_fastx(&input[0], reads_to_save, &tx1).map(|(count, _)| count);
-                drop(tx1);
-                result.wrap_err_with(|| {
-                    format!("Failed to parse first input file: {}", input[0].display())
-                })
-            });
+            let (counts, reads_output) = process_pairs_with(
+                output,
+                output_format,
+                compression_type,
+                compression_level,
+                None,
+                move |txs| {
+                    let mate_sync = match mate_sync {
+                        MateSync::Strict => MateSync::Strict,
+                        MateSync::AllowOrphans(_) => MateSync::AllowOrphans(orphan_tx.as_ref()),
+                    };
+                    reads_to_save
+                        .for_each_pass(|selection| {
+                            parse_paired_fastx(&mut fastx_inputs, selection, txs, mate_sync)
+                        })
+                        .wrap_err("Failed to parse paired input files")
+                },
+            )?;
+            let orphans_output = match orphan_writer {
+                Some(writer) => writer
+                    .join()
+                    .map_err(|_| eyre!("Writer thread for orphan output panicked"))??,
+                None => 0,
+            };
+
+            Ok((counts, reads_output, orphans_output))
+        })?;
 
-            let reader2 = scope.spawn(|| {
-                let result = parse_fastx(&input[1], reads_to_save, &tx2).map(|(count, _)| count);
-                drop(tx2);
-                result.wrap_err_with(|| {
-                    format!("Failed to parse second input file: {}", input[1].display())
-                })
-            });
+    Ok((
+        KractorResult {
+            reads_parsed: counts.pairs * 2 + counts.orphaned_reads,
+            reads_output,
+            input_format,
+            output_format,
+        },
+        OrphanCounts {
+            reads: counts.orphaned_reads,
+            reads_output: orphans_output,
+        },
+    ))
+}
 
-            let writer1 = scope.spawn(|| {
-                write_output_fastx(
-                    rx1,
-                    &output[0],
-                    output_format,
-                    compression_type,
-                    compression_level,
-                )
-                .wrap_err_with(|| {
-                    format!(
-                        "Failed to write output to first file: {}",
-                        output[0].display()
-                    )
-                })
+/// Extracts single-end reads with `--ordered`, walking the input and the Kraken2 output together.
+pub fn process_ordered_single_end(
+    kraken: &mut OrderedKrakenOutput,
+    input: &[PathBuf],
+    output: &[PathBuf],
+    compression_type: Option<niffler::Format>,
+    compression_level: niffler::Level,
+    requested_output_format: OutputFormat,
+) -> Result<KractorResult> {
+    let mut fastx_input = FastxInput::open(&input[0])?;
+    let input_format = fastx_input.format();
+    let output_format = resolve_output_format(input_format, requested_output_format);
+
+    let (total_reads_parsed, total_reads_output) =
+        std::thread::scope(|scope| -> Result<(usize, usize)> {
+            let (tx, rx) = channel::bounded::<FastxRecord>(ORDERED_QUEUE_CAPACITY);
+
+            let reader = scope.spawn(move || {
+                parse_fastx_ordered(&mut fastx_input, kraken, &tx)
+                    .wrap_err_with(|| format!("Failed to parse input file: {}", input[0].display()))
             });
 
-            let writer2 = scope.spawn(|| {
+            let writer = scope.spawn(|| {
                 write_output_fastx(
-                    rx2,
-                    &output[1],
+                    rx,
+                    &output[0],
                     output_format,
                     compression_type,
                     compression_level,
                 )
-                .wrap_err_with(|| {
-                    format!(
-                        "Failed to write output to second file: {}",
-                        output[1].display()
-                    )
-                })
+                .wrap_err_with(|| format!("Failed to write output file: {}", output[0].display()))
             });
 
-            let total_parsed1 = reader1
-                .join()
-                .map_err(|_| eyre!("Reader thread for file1 panicked"))??;
-            let total_parsed2 = reader2
-                .join()
-                .map_err(|_| eyre!("Reader thread for file2 panicked"))??;
-            let total_reads_output1 = writer1
+            let total_reads_parsed = reader
                 .join()
-                .map_err(|_| eyre!("Writer thread for file1 panicked"))??;
-            let total_reads_output2 = writer2
+                .map_err(|_| eyre!("Reader thread for single-end input panicked"))??;
+            let total_reads_output = writer
                 .join()
-                .map_err(|_| eyre!("Writer thread for file2 panicked"))??;
+                .map_err(|_| eyre!("Writer thread for single-end output panicked"))??;
 
-            Ok((
-                (total_parsed1, total_parsed2),
-                (total_reads_output1, total_reads_output2),
-            ))
+            Ok((total_reads_parsed, total_reads_output))
         })?;
 
-    Ok((
-        KractorResult {
-            reads_parsed: reads1,
-            reads_output: out1,
-            input_format,
-            output_format,
+    Ok(KractorResult {
+        reads_parsed: total_reads_parsed,
+        reads_output: total_reads_output,
+        input_format,
+        output_format,
+    })
+}
+
+/// Extracts paired-end reads with `--ordered`. Both inputs are read in step by a single thread, as they share the
+/// lines of the Kraken2 output.
+pub fn process_ordered_paired_end(
+    kraken: &mut OrderedKrakenOutput,
+    input: &[PathBuf],
+    output: &[PathBuf],
+    compression_type: Option<niffler::Format>,
+    compression_level: niffler::Level,
+    requested_output_format: OutputFormat,
+) -> Result<KractorResult> {
+    let mut fastx_inputs = open_paired_inputs(input)?;
+    let input_format = FastxFormat::Fastq;
+    let output_format = resolve_output_format(input_format, requested_output_format);
+
+    let (pairs, reads_output) = process_pairs_with(
+        output,
+        output_format,
+        compression_type,
+        compression_level,
+        Some(ORDERED_QUEUE_CAPACITY),
+        move |txs| {
+            parse_paired_fastx_ordered(&mut fastx_inputs, kraken, txs)
+                .wrap_err("Failed to parse paired input files")
         },
-        KractorResult {
-            reads_parsed: reads2,
-            reads_output: out2,
-            input_format,
-            output_format,
+    )?;
+
+    Ok(KractorResult {
+        reads_parsed: pairs * 2,
+        reads_output,
+        input_format,
+        output_format,
+    })
+}
+
+/// Extracts interleaved paired-end reads, writing the mates to one output each or both to a single output.
+pub fn process_interleaved(
+    reads_to_save: &ReadSelection,
+    input: &[PathBuf],
+    output: &[PathBuf],
+    compression_type: Option<niffler::Format>,
+    compression_level: niffler::Level,
+    requested_output_format: OutputFormat,
+) -> Result<KractorResult> {
+    let mut fastx_input = FastxInput::open(&input[0])?;
+    let input_format = fastx_input.format();
+    let output_format = resolve_output_format(input_format, requested_output_format);
+
+    let (pairs, reads_output) = process_pairs_with(
+        output,
+        output_format,
+        compression_type,
+        compression_level,
+        None,
+        |txs| {
+            reads_to_save
+                .for_each_pass(|selection| {
+                    parse_interleaved_fastx(&mut fastx_input, selection, txs)
+                })
+                .wrap_err_with(|| format!("Failed to parse input file: {}", input[0].display()))
         },
-    ))
+    )?;
+
+    Ok(KractorResult {
+        reads_parsed: pairs * 2,
+        reads_output,
+        input_format,
+        output_format,
+    })
+}
+
+/// Extracts interleaved paired-end reads with `--ordered`, walking the input and the Kraken2 output together.
+pub fn process_ordered_interleaved(
+    kraken: &mut OrderedKrakenOutput,
+    input: &[PathBuf],
+    output: &[PathBuf],
+    compression_type: Option<niffler::Format>,
+    compression_level: niffler::Level,
+    requested_output_format: OutputFormat,
+) -> Result<KractorResult> {
+    let mut fastx_input = FastxInput::open(&input[0])?;
+    let input_format = fastx_input.format();
+    let output_format = resolve_output_format(input_format, requested_output_format);
+
+    let (pairs, reads_output) = process_pairs_with(
+        output,
+        output_format,
+        compression_type,
+        compression_level,
+        Some(ORDERED_QUEUE_CAPACITY),
+        move |txs| {
+            parse_interleaved_fastx_ordered(&mut fastx_input, kraken, txs)
+                .wrap_err_with(|| format!("Failed to parse input file: {}", input[0].display()))
+        },
+    )?;
+
+    Ok(KractorResult {
+        reads_parsed: pairs * 2,
+        reads_output,
+        input_format,
+        output_format,
+    })
+}
+
+// runs `parse` on a single reader thread with a writer for each output, using bounded queues when a capacity is given.
+// Returns the result of `parse` and the number of records written
+fn process_pairs_with<T: Send>(
+    output: &[PathBuf],
+    output_format: FastxFormat,
+    compression_type: Option<niffler::Format>,
+    compression_level: niffler::Level,
+    queue_capacity: Option<usize>,
+    parse: impl FnOnce(&[Sender<FastxRecord>]) -> Result<T> + Send,
+) -> Result<(T, usize)> {
+    std::thread::scope(|scope| -> Result<(T, usize)> {
+        let (txs, rxs): (Vec<_>, Vec<_>) = output
+            .iter()
+            .map(|_| match queue_capacity {
+                Some(capacity) => channel::bounded::<FastxRecord>(capacity),
+                None => channel::unbounded::<FastxRecord>(),
+            })
+            .unzip();
+
+        let reader = scope.spawn(move || parse(&txs));
+
+        let writers: Vec<_> = rxs
+            .into_iter()
+            .zip(output)
+            .map(|(rx, out_file)| {
+                scope.spawn(move || {
+                    write_output_fastx(
+                        rx,
+                        out_file,
+                        output_format,
+                        compression_type,
+                        compression_level,
+                    )
+                    .wrap_err_with(|| {
+                        format!("Failed to write output file: {}", out_file.display())
+                    })
+                })
+            })
+            .collect();
+
+        let parsed = reader
+            .join()
+            .map_err(|_| eyre!("Reader thread for paired input panicked"))??;
+        let mut reads_output = 0;
+        for writer in writers {
+            reads_output += writer
+                .join()
+                .map_err(|_| eyre!("Writer thread for paired output panicked"))??;
+        }
+
+        Ok((parsed, reads_output))
+    })
+}
+
+fn open_paired_inputs(input: &[PathBuf]) -> Result<[FastxInput; 2]> {
+    let input1 = FastxInput::open(&input[0]).wrap_err("Failed to open first input file")?;
+    let input2 = FastxInput::open(&input[1]).wrap_err("Failed to open second input file")?;
+
+    if input1.format() == FastxFormat::Fasta || input2.format() == FastxFormat::Fasta {
+        bail!("Two input files are not supported for FASTA input");
+    }
+
+    Ok([input1, input2])
+}
+
+/// How the requested taxon IDs are expanded through the taxonomic tree of the Kraken2 report.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TaxonExpansion {
+    pub children: bool,
+    pub parents: bool,
+    /// Stop climbing to the parents at this rank or taxon ID, rather than at the root.
+    pub parents_up_to: Option<ParentLimit>,
+    /// Replace each taxon with the whole clade of its ancestor at this rank.
+    pub clade_rank: Option<Rank>,
+    /// Keep only the added taxa at this rank or below it.
+    pub min_rank: Option<Rank>,
+    /// Keep only the added taxa at this rank or above it.
+    pub max_rank: Option<Rank>,
+}
+
+impl TaxonExpansion {
+    fn filters_ranks(&self) -> bool {
+        self.min_rank.is_some() || self.max_rank.is_some()
+    }
+
+    // taxa without a standard rank only pass when no rank limit is set
+    fn rank_in_range(&self, rank: Option<Rank>) -> bool {
+        if !self.filters_ranks() {
+            return true;
+        }
+        rank.is_some_and(|rank| {
+            self.min_rank.is_none_or(|min_rank| rank >= min_rank)
+                && self.max_rank.is_none_or(|max_rank| rank <= max_rank)
+        })
+    }
+}
+
+/// How taxon names are matched against the scientific names in the Kraken2 report.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NameMatching {
+    pub ignore_case: bool,
+    /// Treat each name as a regular expression, which may select several taxa.
+    pub regex: bool,
+}
+
+enum NamePattern {
+    Exact(String),
+    IgnoreCase(String),
+    Regex(Regex),
+}
+
+impl NamePattern {
+    fn is_match(&self, name: &str) -> bool {
+        match self {
+            Self::Exact(pattern) => name == pattern,
+            Self::IgnoreCase(pattern) => name.to_lowercase() == *pattern,
+            Self::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+impl NameMatching {
+    fn pattern(self, name: &str) -> Result<NamePattern> {
+        let name = name.trim();
+        if self.regex {
+            let regex = RegexBuilder::new(name)
+                .case_insensitive(self.ignore_case)
+                .build()
+                .wrap_err_with(|| format!("Invalid taxon name regex: {name}"))?;
+            Ok(NamePattern::Regex(regex))
+        } else if self.ignore_case {
+            Ok(NamePattern::IgnoreCase(name.to_lowercase()))
+        } else {
+            Ok(NamePattern::Exact(name.to_string()))
+        }
+    }
+
+    /// Checks each name can be matched, so an invalid regex fails before any reads are processed.
+    pub fn validate(self, names: &[String]) -> Result<()> {
+        for name in names {
+            self.pattern(name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Resolves each taxon name to the indices of the nodes it matches in the tree. A name must match a taxon, and unless
+/// it is a regex it must match only one.
+fn resolve_taxon_names(
+    nodes: &[Tree],
+    names: &[String],
+    matching: NameMatching,
+) -> Result<Vec<(String, Vec<usize>)>> {
+    let describe = |index: usize| {
+        let node = &nodes[index];
+        let rank = node
+            .rank
+            .map_or_else(|| "no rank".to_string(), |rank| rank.to_string());
+        match node.parent {
+            Some(parent) => format!("{} ({rank}, in {})", node.taxon_id, nodes[parent].name),
+            None => format!("{} ({rank})", node.taxon_id),
+        }
+    };
+
+    let mut resolved = Vec::with_capacity(names.len());
+    for name in names {
+        let pattern = matching.pattern(name)?;
+        let matches: Vec<usize> = nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| pattern.is_match(&node.name))
+            .map(|(index, _)| index)
+            .collect();
+
+        if matches.is_empty() {
+            // point out a match that only differs in case, the most likely reason for a miss
+            let near_miss = (!matching.ignore_case && !matching.regex)
+                .then(|| {
+                    let lowercase = name.trim().to_lowercase();
+                    nodes
+                        .iter()
+                        .find(|node| node.name.to_lowercase() == lowercase)
+                })
+                .flatten();
+            match near_miss {
+                Some(node) => bail!(
+                    "Taxon name \"{name}\" was not found in the kraken report. Did you mean \"{}\"? Use --ignore-case to match names in any case",
+                    node.name
+                ),
+                None => bail!("Taxon name \"{name}\" was not found in the kraken report"),
+            }
+        }
+        if matches.len() > 1 && !matching.regex {
+            let candidates: Vec<String> = matches.iter().map(|&index| describe(index)).collect();
+            bail!(
+                "Taxon name \"{name}\" is ambiguous, it matches {} taxa in the kraken report: {}. Use --taxid to choose one",
+                matches.len(),
+                candidates.join(", ")
+            );
+        }
+        debug!(
+            "Taxon name \"{name}\" resolved to {}",
+            matches
+                .iter()
+                .map(|&index| describe(index))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        resolved.push((name.clone(), matches));
+    }
+    Ok(resolved)
 }
 
 pub fn collect_taxa_to_save(
     report: Option<&Path>,
-    children: bool,
-    parents: bool,
+    expansion: &TaxonExpansion,
     taxids: &[i32],
+    taxon_names: &[String],
+    name_matching: NameMatching,
     detect_report_header: bool,
 ) -> Result<CollectedTaxonIds> {
-    let mut taxon_ids_to_save = Vec::new();
+    let mut origins = FxHashMap::default();
     let mut missing_taxon_ids = Vec::new();
+    let mut tree_nodes = Vec::new();
+    let mut resolved_names = Vec::new();
 
     // I dont think we will reach this code ever since clap should catch this - but in case it doesnt
-    if (parents || children) && report.is_none() {
-        return Err(eyre!("Report required when parents or children is enabled"));
+    if (expansion.parents
+        || expansion.children
+        || expansion.clade_rank.is_some()
+        || expansion.filters_ranks()
+        || !taxon_names.is_empty())
+        && report.is_none()
+    {
+        return Err(eyre!(
+            "Report required when parents, children, clades, a rank limit or taxon names are enabled"
+        ));
+    }
+    if let (Some(min_rank), Some(max_rank)) = (expansion.min_rank, expansion.max_rank) {
+        ensure!(
+            min_rank <= max_rank,
+            "--min-rank {min_rank} is below --max-rank {max_rank}, so no taxa can be added"
+        );
     }
 
     if let Some(report_path) = report {
         let ProcessedKrakenTree {
             nodes,
-            taxon_map,
+            mut taxon_map,
             missing_taxon_ids: missing_ids,
         } = build_tree_from_kraken_report(taxids, report_path, detect_report_header)?;
 
@@ -235,40 +637,106 @@ pub fn collect_taxa_to_save(
         missing_taxon_ids = missing_ids;
 
         // remove missing taxon ids from the input list
-        let taxids: Vec<i32> = taxids
+        let mut taxids: Vec<i32> = taxids
             .iter()
             .filter(|id| !missing_taxon_ids.contains(id))
             .copied()
             .collect();
 
+        for (name, indices) in resolve_taxon_names(&nodes, taxon_names, name_matching)? {
+            let taxon_ids: Vec<i32> = indices
+                .iter()
+                .map(|&index| {
+                    let taxon_id = nodes[index].taxon_id;
+                    taxon_map.insert(taxon_id, index);
+                    taxon_id
+                })
+                .collect();
+            info!("Taxon name \"{name}\" resolved to taxon ID(s) {taxon_ids:?}");
+            taxids.extend(&taxon_ids);
+            resolved_names.push(ResolvedTaxonName { name, taxon_ids });
+        }
+
         if taxon_map.is_empty() {
             bail!("No valid taxon IDs found in the kraken report");
         }
 
-        if children {
+        for &taxid in &taxids {
+            add_taxon(&mut origins, taxid, TaxonOrigin::Requested);
+        }
+        // with both, the subtree and the lineage of each taxon are merged
+        if expansion.children {
             debug!("Extracting children");
             let mut children = Vec::new();
-            for taxid in taxids {
+            for &taxid in &taxids {
                 if let Some(&node_index) = taxon_map.get(&taxid) {
                     extract_children(&nodes, &mut children, node_index)?;
                 }
             }
-            taxon_ids_to_save.extend(children);
-        } else if parents {
+            for child in children {
+                add_taxon(&mut origins, child, TaxonOrigin::Descendant);
+            }
+        }
+        if expansion.parents {
             debug!("Extracting parents");
-            for taxid in taxids {
-                taxon_ids_to_save.extend(extract_parents(&taxon_map, &nodes, taxid)?);
+            for &taxid in &taxids {
+                for parent in extract_parents(&taxon_map, &nodes, taxid, expansion.parents_up_to)? {
+                    add_taxon(&mut origins, parent, TaxonOrigin::Ancestor);
+                }
             }
-        } else {
-            taxon_ids_to_save.extend(taxids);
         }
+
+        if let Some(clade_rank) = expansion.clade_rank {
+            debug!("Extracting clades at rank {clade_rank}");
+            for &taxid in &taxids {
+                let Some(&node_index) = taxon_map.get(&taxid) else {
+                    continue;
+                };
+                let clade_index =
+                    find_ancestor_at_rank(&nodes, node_index, clade_rank).ok_or_else(|| {
+                        eyre!(
+                            "taxon ID {taxid} has no ancestor at rank {clade_rank} in the kraken report, so its clade cannot be extracted"
+                        )
+                    })?;
+                info!(
+                    "Taxon ID {taxid} expanded to the {clade_rank} clade of {} ({})",
+                    nodes[clade_index].taxon_id, nodes[clade_index].name
+                );
+                let mut clade = Vec::new();
+                extract_children(&nodes, &mut clade, clade_index)?;
+                for member in clade {
+                    add_taxon(&mut origins, member, TaxonOrigin::Clade);
+                }
+            }
+        }
+
+        if expansion.filters_ranks() {
+            let ranks: FxHashMap<i32, Option<Rank>> = nodes
+                .iter()
+                .map(|node| (node.taxon_id, node.rank))
+                .collect();
+            let before = origins.len();
+            // the limits only trim the taxa added by the expansions, never the ones asked for
+            origins.retain(|taxid, origin| {
+                *origin == TaxonOrigin::Requested
+                    || expansion.rank_in_range(ranks.get(taxid).copied().flatten())
+            });
+            debug!(
+                "Dropped {} added taxon IDs outside the rank limits",
+                before - origins.len()
+            );
+        }
+
+        tree_nodes = nodes;
     } else {
         debug!("No kraken report provided - extracting reads for taxon ID {taxids:?} only");
-        taxon_ids_to_save.extend(taxids);
+        for &taxid in taxids {
+            add_taxon(&mut origins, taxid, TaxonOrigin::Requested);
+        }
     }
 
+    let mut taxon_ids_to_save: Vec<i32> = origins.keys().copied().collect();
     taxon_ids_to_save.sort_unstable();
-    taxon_ids_to_save.dedup();
 
     missing_taxon_ids.sort_unstable();
     missing_taxon_ids.dedup();
@@ -281,6 +749,9 @@ pub fn collect_taxa_to_save(
     Ok(CollectedTaxonIds {
         found: taxon_ids_to_save,
         missing: missing_taxon_ids,
+        nodes: tree_nodes,
+        origins,
+        resolved_names,
     })
 }
 
@@ -300,8 +771,8 @@ mod tests {
         let test_data = "@read1\nAAAA\n+\n!!!!\n@read2\nGGGG\n+\n!!!!\n";
         let mut file = File::create(&input_path).unwrap();
         file.write_all(test_data.as_bytes()).unwrap();
-        let mut reads_to_save = FxHashSet::default();
-        reads_to_save.insert(b"read1".to_vec());
+        let mut reads_to_save = ReadSelection::default();
+        reads_to_save.keep(b"read1");
         let input = vec![input_path];
         let output = vec![output_path.clone()];
         let KractorResult {
@@ -337,8 +808,8 @@ mod tests {
         let test_data = "@read1\nAAAA\n+\n!!!!\n@read2\nGGGG\n+\n!!!!\n";
         let mut file = File::create(&input_path).unwrap();
         file.write_all(test_data.as_bytes()).unwrap();
-        let mut reads_to_save = FxHashSet::default();
-        reads_to_save.insert(b"read1".to_vec());
+        let mut reads_to_save = ReadSelection::default();
+        reads_to_save.keep(b"read1");
         let input = vec![input_path];
         let output = vec![output_path.clone()];
         let KractorResult {
@@ -374,8 +845,8 @@ mod tests {
         let test_data = ">read1 some description\nAAAA\n>read2 another description\nGGGG\n";
         let mut file = File::create(&input_path).unwrap();
         file.write_all(test_data.as_bytes()).unwrap();
-        let mut reads_to_save = FxHashSet::default();
-        reads_to_save.insert(b"read1".to_vec());
+        let mut reads_to_save = ReadSelection::default();
+        reads_to_save.keep(b"read1");
         let input = vec![input_path];
         let output = vec![output_path.clone()];
         let KractorResult {
@@ -412,8 +883,8 @@ mod tests {
         let test_data = ">read1\nAAAA\n";
         let mut file = File::create(&input_path).unwrap();
         file.write_all(test_data.as_bytes()).unwrap();
-        let mut reads_to_save = FxHashSet::default();
-        reads_to_save.insert(b"read1".to_vec());
+        let mut reads_to_save = ReadSelection::default();
+        reads_to_save.keep(b"read1");
         let input = vec![input_path];
         let output = vec![output_path.clone()];
         let KractorResult {
@@ -445,7 +916,7 @@ mod tests {
     fn test_process_single_end_not_found() {
         let nonexistent_path = PathBuf::from("idontexist.fastq");
         let output_path = PathBuf::from("output.fastq");
-        let reads_to_save = FxHashSet::default();
+        let reads_to_save = ReadSelection::default();
         let input = vec![nonexistent_path];
         let output = vec![output_path];
 
@@ -474,22 +945,18 @@ mod tests {
         file1.write_all(test_data1.as_bytes()).unwrap();
         let mut file2 = File::create(&input_path2).unwrap();
         file2.write_all(test_data2.as_bytes()).unwrap();
-        let mut reads_to_save = FxHashSet::default();
-        reads_to_save.insert(b"read1".to_vec());
+        let mut reads_to_save = ReadSelection::default();
+        reads_to_save.keep(b"read1");
         let input = vec![input_path1, input_path2];
         let output = vec![output_path1.clone(), output_path2.clone()];
         let (
             KractorResult {
-                reads_parsed: reads_parsed1,
-                reads_output: reads_output1,
+                reads_parsed,
+                reads_output,
                 input_format,
                 output_format,
             },
-            KractorResult {
-                reads_parsed: reads_parsed2,
-                reads_output: reads_output2,
-                ..
-            },
+            _,
         ) = process_paired_end(
             &reads_to_save,
             &input,
@@ -497,17 +964,16 @@ mod tests {
             Some(niffler::Format::No),
             niffler::Level::One,
             OutputFormat::Auto,
+            MateSync::Strict,
         )
         .unwrap();
         let file_content1 = std::fs::read_to_string(output_path1).unwrap();
         let file_content2 = std::fs::read_to_string(output_path2).unwrap();
 
-        assert_eq!(reads_output1, 1);
-        assert_eq!(reads_parsed1, 2);
-        assert_eq!(reads_parsed2, 2);
+        assert_eq!(reads_output, 2);
+        assert_eq!(reads_parsed, 4);
         assert_eq!(input_format, FastxFormat::Fastq);
         assert_eq!(output_format, FastxFormat::Fastq);
-        assert_eq!(reads_output2, 1);
         assert!(file_content1.contains("@read1"));
         assert!(file_content1.contains("AAAA"));
         assert!(!file_content1.contains("@read2"));
@@ -515,6 +981,89 @@ mod tests {
         assert!(file_content2.contains("TTTT"));
     }
 
+    #[test]
+    fn test_process_paired_end_mate_suffixes() {
+        let dir = tempdir().unwrap();
+        let input = vec![
+            dir.path().join("input1.fastq"),
+            dir.path().join("input2.fastq"),
+        ];
+        let output = vec![
+            dir.path().join("output1.fastq"),
+            dir.path().join("output2.fastq"),
+        ];
+        std::fs::write(
+            &input[0],
+            "@read1/1\nAAAA\n+\n!!!!\n@read2/1\nGGGG\n+\n!!!!\n",
+        )
+        .unwrap();
+        std::fs::write(
+            &input[1],
+            "@read1/2\nTTTT\n+\n!!!!\n@read2/2\nCCCC\n+\n!!!!\n",
+        )
+        .unwrap();
+        let mut reads_to_save = ReadSelection::default();
+        reads_to_save.keep(b"read2");
+
+        let (result, _) = process_paired_end(
+            &reads_to_save,
+            &input,
+            &output,
+            Some(niffler::Format::No),
+            niffler::Level::One,
+            OutputFormat::Auto,
+            MateSync::Strict,
+        )
+        .unwrap();
+
+        assert_eq!(result.reads_output, 2);
+        assert_eq!(
+            std::fs::read_to_string(&output[0]).unwrap(),
+            "@read2/1\nGGGG\n+\n!!!!\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&output[1]).unwrap(),
+            "@read2/2\nCCCC\n+\n!!!!\n"
+        );
+    }
+
+    #[test]
+    fn test_process_paired_end_interleaved_output() {
+        let dir = tempdir().unwrap();
+        let input_path1 = dir.path().join("input1.fastq");
+        let input_path2 = dir.path().join("input2.fastq");
+        let output_path = dir.path().join("output.fastq");
+        let test_data1 = "@read1\nAAAA\n+\n!!!!\n@read2\nGGGG\n+\n!!!!\n@read3\nAAGG\n+\n!!!!\n";
+        let test_data2 = "@read1\nTTTT\n+\n!!!!\n@read2\nCCCC\n+\n!!!!\n@read3\nTTCC\n+\n!!!!\n";
+        let mut file1 = File::create(&input_path1).unwrap();
+        file1.write_all(test_data1.as_bytes()).unwrap();
+        let mut file2 = File::create(&input_path2).unwrap();
+        file2.write_all(test_data2.as_bytes()).unwrap();
+        let mut reads_to_save = ReadSelection::default();
+        reads_to_save.keep(b"read1");
+        reads_to_save.keep(b"read3");
+        let input = vec![input_path1, input_path2];
+        let output = vec![output_path.clone()];
+        let (result, _) = process_paired_end(
+            &reads_to_save,
+            &input,
+            &output,
+            Some(niffler::Format::No),
+            niffler::Level::One,
+            OutputFormat::Auto,
+            MateSync::Strict,
+        )
+        .unwrap();
+        let file_content = std::fs::read_to_string(output_path).unwrap();
+
+        assert_eq!(result.reads_parsed, 6);
+        assert_eq!(result.reads_output, 4);
+        assert_eq!(
+            file_content,
+            "@read1\nAAAA\n+\n!!!!\n@read1\nTTTT\n+\n!!!!\n@read3\nAAGG\n+\n!!!!\n@read3\nTTCC\n+\n!!!!\n"
+        );
+    }
+
     #[test]
     fn test_process_paired_end_fasta() {
         let dir = tempdir().unwrap();
@@ -528,22 +1077,18 @@ mod tests {
         file1.write_all(test_data1.as_bytes()).unwrap();
         let mut file2 = File::create(&input_path2).unwrap();
         file2.write_all(test_data2.as_bytes()).unwrap();
-        let mut reads_to_save = FxHashSet::default();
-        reads_to_save.insert(b"read1".to_vec());
+        let mut reads_to_save = ReadSelection::default();
+        reads_to_save.keep(b"read1");
         let input = vec![input_path1, input_path2];
         let output = vec![output_path1.clone(), output_path2.clone()];
         let (
             KractorResult {
-                reads_parsed: reads_parsed1,
-                reads_output: reads_output1,
+                reads_parsed,
+                reads_output,
                 input_format,
                 output_format,
             },
-            KractorResult {
-                reads_parsed: reads_parsed2,
-                reads_output: reads_output2,
-                ..
-            },
+            _,
         ) = process_paired_end(
             &reads_to_save,
             &input,
@@ -551,17 +1096,16 @@ mod tests {
             Some(niffler::Format::No),
             niffler::Level::One,
             OutputFormat::Fasta,
+            MateSync::Strict,
         )
         .unwrap();
         let file_content1 = std::fs::read_to_string(output_path1).unwrap();
         let file_content2 = std::fs::read_to_string(output_path2).unwrap();
 
-        assert_eq!(reads_output1, 1);
-        assert_eq!(reads_parsed1, 2);
-        assert_eq!(reads_parsed2, 2);
+        assert_eq!(reads_output, 2);
+        assert_eq!(reads_parsed, 4);
         assert_eq!(input_format, FastxFormat::Fastq);
         assert_eq!(output_format, FastxFormat::Fasta);
-        assert_eq!(reads_output2, 1);
         assert!(file_content1.contains(">read1"));
         assert!(file_content1.contains("AAAA"));
         assert!(!file_content1.contains("@read2"));
@@ -582,8 +1126,8 @@ mod tests {
         file1.write_all(test_data1.as_bytes()).unwrap();
         let mut file2 = File::create(&input_path2).unwrap();
         file2.write_all(test_data2.as_bytes()).unwrap();
-        let mut reads_to_save = FxHashSet::default();
-        reads_to_save.insert(b"read1".to_vec());
+        let mut reads_to_save = ReadSelection::default();
+        reads_to_save.keep(b"read1");
         let input = vec![input_path1, input_path2];
         let output = vec![output_path1, output_path2];
 
@@ -594,11 +1138,99 @@ mod tests {
             Some(niffler::Format::No),
             niffler::Level::One,
             OutputFormat::Auto,
+            MateSync::Strict,
         );
 
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_process_paired_end_out_of_sync() {
+        let dir = tempdir().unwrap();
+        let input_path1 = dir.path().join("input1.fastq");
+        let input_path2 = dir.path().join("input2.fastq");
+        let test_data1 =
+            "@read1/1\nAAAA\n+\n!!!!\n@read2/1\nGGGG\n+\n!!!!\n@read3/1\nAAGG\n+\n!!!!\n";
+        let test_data2 =
+            "@read1/2\nTTTT\n+\n!!!!\n@read3/2\nTTCC\n+\n!!!!\n@read4/2\nCCAA\n+\n!!!!\n";
+        let mut file1 = File::create(&input_path1).unwrap();
+        file1.write_all(test_data1.as_bytes()).unwrap();
+        let mut file2 = File::create(&input_path2).unwrap();
+        file2.write_all(test_data2.as_bytes()).unwrap();
+        // Kraken2 --paired writes the read IDs without their mate suffix
+        let mut reads_to_save = ReadSelection::default();
+        reads_to_save.keep(b"read2");
+        reads_to_save.keep(b"read3");
+        reads_to_save.keep(b"read4");
+        let input = vec![input_path1, input_path2];
+        let output = vec![
+            dir.path().join("output1.fastq"),
+            dir.path().join("output2.fastq"),
+        ];
+        let orphan_output = dir.path().join("orphans.fastq");
+
+        let err = process_paired_end(
+            &reads_to_save,
+            &input,
+            &output,
+            Some(niffler::Format::No),
+            niffler::Level::One,
+            OutputFormat::Auto,
+            MateSync::Strict,
+        )
+        .unwrap_err();
+        assert!(format!("{err:?}").contains("out of sync at pair 2"));
+
+        let output = vec![
+            dir.path().join("lenient1.fastq"),
+            dir.path().join("lenient2.fastq"),
+        ];
+        let (result, orphans) = process_paired_end(
+            &reads_to_save,
+            &input,
+            &output,
+            Some(niffler::Format::No),
+            niffler::Level::One,
+            OutputFormat::Auto,
+            MateSync::AllowOrphans(Some(orphan_output.as_path())),
+        )
+        .unwrap();
+
+        assert_eq!(result.reads_parsed, 6);
+        assert_eq!(result.reads_output, 2);
+        assert_eq!(orphans.reads, 2);
+        assert_eq!(orphans.reads_output, 2);
+        assert_eq!(
+            std::fs::read_to_string(&output[0]).unwrap(),
+            "@read3/1\nAAGG\n+\n!!!!\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&output[1]).unwrap(),
+            "@read3/2\nTTCC\n+\n!!!!\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(orphan_output).unwrap(),
+            "@read2/1\nGGGG\n+\n!!!!\n@read4/2\nCCAA\n+\n!!!!\n"
+        );
+    }
+
+    const CHILDREN: TaxonExpansion = TaxonExpansion {
+        children: true,
+        parents: false,
+        parents_up_to: None,
+        clade_rank: None,
+        min_rank: None,
+        max_rank: None,
+    };
+    const PARENTS: TaxonExpansion = TaxonExpansion {
+        children: false,
+        parents: true,
+        parents_up_to: None,
+        clade_rank: None,
+        min_rank: None,
+        max_rank: None,
+    };
+
     fn create_test_kraken_report(dir: &tempfile::TempDir) -> PathBuf {
         let report_path = dir.path().join("report.txt");
         let test_data = "\
@@ -621,16 +1253,25 @@ mod tests {
 
     #[test]
     fn test_error_when_no_report_and_parents_or_children() {
-        let result = collect_taxa_to_save(None, true, false, &[1], true);
+        let result =
+            collect_taxa_to_save(None, &CHILDREN, &[1], &[], NameMatching::default(), true);
         assert!(result.is_err());
-        let result = collect_taxa_to_save(None, false, true, &[1], true);
+        let result = collect_taxa_to_save(None, &PARENTS, &[1], &[], NameMatching::default(), true);
         assert!(result.is_err());
     }
 
     #[test]
     fn test_no_report() {
         let taxids = vec![123, 456, 789];
-        let collected = collect_taxa_to_save(None, false, false, &taxids, true).unwrap();
+        let collected = collect_taxa_to_save(
+            None,
+            &TaxonExpansion::default(),
+            &taxids,
+            &[],
+            NameMatching::default(),
+            true,
+        )
+        .unwrap();
 
         assert_eq!(collected.found, taxids);
         assert!(collected.missing.is_empty());
@@ -641,8 +1282,15 @@ mod tests {
         let dir = tempdir().unwrap();
         let report_path = create_test_kraken_report(&dir);
         let taxids = vec![0, 2];
-        let collected =
-            collect_taxa_to_save(Some(report_path.as_path()), false, false, &taxids, true).unwrap();
+        let collected = collect_taxa_to_save(
+            Some(report_path.as_path()),
+            &TaxonExpansion::default(),
+            &taxids,
+            &[],
+            NameMatching::default(),
+            true,
+        )
+        .unwrap();
 
         assert_eq!(collected.found, vec![0, 2]);
         assert!(collected.missing.is_empty());
@@ -653,8 +1301,15 @@ mod tests {
         let dir = tempdir().unwrap();
         let report_path = create_test_kraken_report(&dir);
         let taxids = vec![1385, 1386, 91061];
-        let collected =
-            collect_taxa_to_save(Some(report_path.as_path()), false, false, &taxids, true).unwrap();
+        let collected = collect_taxa_to_save(
+            Some(report_path.as_path()),
+            &TaxonExpansion::default(),
+            &taxids,
+            &[],
+            NameMatching::default(),
+            true,
+        )
+        .unwrap();
 
         assert_eq!(collected.found, taxids);
         assert!(collected.missing.is_empty());
@@ -665,8 +1320,15 @@ mod tests {
         let dir = tempdir().unwrap();
         let report_path = create_test_kraken_report(&dir);
         let taxids = vec![1239];
-        let collected =
-            collect_taxa_to_save(Some(report_path.as_path()), true, false, &taxids, true).unwrap();
+        let collected = collect_taxa_to_save(
+            Some(report_path.as_path()),
+            &CHILDREN,
+            &taxids,
+            &[],
+            NameMatching::default(),
+            true,
+        )
+        .unwrap();
 
         assert!(collected.found.contains(&1239));
         assert!(collected.found.contains(&91062));
@@ -678,8 +1340,15 @@ mod tests {
         let dir = tempdir().unwrap();
         let report_path = create_test_kraken_report(&dir);
         let taxids = vec![91061];
-        let collected =
-            collect_taxa_to_save(Some(report_path.as_path()), false, true, &taxids, true).unwrap();
+        let collected = collect_taxa_to_save(
+            Some(report_path.as_path()),
+            &PARENTS,
+            &taxids,
+            &[],
+            NameMatching::default(),
+            true,
+        )
+        .unwrap();
 
         assert!(collected.found.contains(&91061));
         assert!(collected.found.contains(&1239));
@@ -688,12 +1357,300 @@ mod tests {
         assert!(collected.found.contains(&2));
     }
 
+    #[test]
+    fn test_parents_up_to() {
+        let dir = tempdir().unwrap();
+        let report_path = create_test_kraken_report(&dir);
+        let expansion = TaxonExpansion {
+            parents_up_to: Some(ParentLimit::Rank("P".parse().unwrap())),
+            ..PARENTS
+        };
+        let collected = collect_taxa_to_save(
+            Some(report_path.as_path()),
+            &expansion,
+            &[1386, 1385],
+            &[],
+            NameMatching::default(),
+            true,
+        )
+        .unwrap();
+
+        assert_eq!(collected.found, vec![1239, 1385, 1386, 91061, 186817]);
+        // Bacillales is requested, even though it is also an ancestor of Bacillus
+        assert_eq!(collected.origins[&1385], TaxonOrigin::Requested);
+        assert_eq!(collected.origins[&186817], TaxonOrigin::Ancestor);
+    }
+
+    #[test]
+    fn test_parents_and_children() {
+        let dir = tempdir().unwrap();
+        let report_path = create_test_kraken_report(&dir);
+        let expansion = TaxonExpansion {
+            children: true,
+            parents: true,
+            parents_up_to: Some(ParentLimit::Rank("C".parse().unwrap())),
+            ..Default::default()
+        };
+        let collected = collect_taxa_to_save(
+            Some(report_path.as_path()),
+            &expansion,
+            &[1385],
+            &[],
+            NameMatching::default(),
+            true,
+        )
+        .unwrap();
+
+        assert_eq!(collected.found, vec![1385, 1386, 91061, 186817]);
+        let origins: Vec<TaxonOrigin> = collected
+            .found
+            .iter()
+            .map(|taxid| collected.origins[taxid])
+            .collect();
+        assert_eq!(
+            origins,
+            vec![
+                TaxonOrigin::Requested,
+                TaxonOrigin::Descendant,
+                TaxonOrigin::Ancestor,
+                TaxonOrigin::Descendant,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_clade_rank() {
+        let dir = tempdir().unwrap();
+        let report_path = create_test_kraken_report(&dir);
+        let expansion = TaxonExpansion {
+            clade_rank: Some("O".parse().unwrap()),
+            ..Default::default()
+        };
+        let collected = collect_taxa_to_save(
+            Some(report_path.as_path()),
+            &expansion,
+            &[1386],
+            &[],
+            NameMatching::default(),
+            true,
+        )
+        .unwrap();
+
+        assert_eq!(collected.found, vec![1385, 1386, 186817]);
+        assert_eq!(collected.origins[&1386], TaxonOrigin::Requested);
+        assert_eq!(collected.origins[&1385], TaxonOrigin::Clade);
+
+        // Bacillota is above the rank, so it has no order to expand to
+        let result = collect_taxa_to_save(
+            Some(report_path.as_path()),
+            &expansion,
+            &[1239],
+            &[],
+            NameMatching::default(),
+            true,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_rank_limits() {
+        let dir = tempdir().unwrap();
+        let report_path = create_test_kraken_report(&dir);
+        let rank = |code: &str| Some(code.parse::<Rank>().unwrap());
+
+        let expansion = TaxonExpansion {
+            children: true,
+            min_rank: rank("O"),
+            max_rank: rank("F"),
+            ..Default::default()
+        };
+        let collected = collect_taxa_to_save(
+            Some(report_path.as_path()),
+            &expansion,
+            &[1239],
+            &[],
+            NameMatching::default(),
+            true,
+        )
+        .unwrap();
+        let mut found = collected.found;
+        found.sort();
+        assert_eq!(found, vec![1239, 1385, 186817]);
+
+        // intermediate ranks sit below their standard rank, so R1 is kept by --max-rank D but D1 is not, while the
+        // requested phylum is kept whatever its rank
+        let expansion = TaxonExpansion {
+            parents: true,
+            max_rank: rank("D"),
+            ..Default::default()
+        };
+        let collected = collect_taxa_to_save(
+            Some(report_path.as_path()),
+            &expansion,
+            &[1239],
+            &[],
+            NameMatching::default(),
+            true,
+        )
+        .unwrap();
+        let mut found = collected.found;
+        found.sort();
+        assert_eq!(found, vec![1, 2, 1239, 131567]);
+
+        let expansion = TaxonExpansion {
+            min_rank: rank("S"),
+            ..Default::default()
+        };
+        let collected = collect_taxa_to_save(
+            Some(report_path.as_path()),
+            &expansion,
+            &[1386],
+            &[],
+            NameMatching::default(),
+            true,
+        )
+        .unwrap();
+        assert_eq!(collected.found, vec![1386]);
+
+        let expansion = TaxonExpansion {
+            min_rank: rank("G"),
+            max_rank: rank("F"),
+            ..Default::default()
+        };
+        assert!(
+            collect_taxa_to_save(
+                Some(report_path.as_path()),
+                &expansion,
+                &[1386],
+                &[],
+                NameMatching::default(),
+                true
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_taxon_names() {
+        let dir = tempdir().unwrap();
+        let report_path = create_test_kraken_report(&dir);
+        let names = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let collected = collect_taxa_to_save(
+            Some(report_path.as_path()),
+            &TaxonExpansion::default(),
+            &[2],
+            &names(&["Bacillaceae", " Bacillota "]),
+            NameMatching::default(),
+            true,
+        )
+        .unwrap();
+        assert_eq!(collected.found, vec![2, 1239, 186817]);
+        assert_eq!(
+            collected.resolved_names,
+            vec![
+                ResolvedTaxonName {
+                    name: "Bacillaceae".to_string(),
+                    taxon_ids: vec![186817],
+                },
+                ResolvedTaxonName {
+                    name: " Bacillota ".to_string(),
+                    taxon_ids: vec![1239],
+                },
+            ]
+        );
+
+        // names resolve before the tree is expanded
+        let collected = collect_taxa_to_save(
+            Some(report_path.as_path()),
+            &CHILDREN,
+            &[],
+            &names(&["bacillales"]),
+            NameMatching {
+                ignore_case: true,
+                regex: false,
+            },
+            true,
+        )
+        .unwrap();
+        assert_eq!(collected.found, vec![1385, 1386, 186817]);
+
+        let collected = collect_taxa_to_save(
+            Some(report_path.as_path()),
+            &TaxonExpansion::default(),
+            &[],
+            &names(&["^Bacilli\\d?$"]),
+            NameMatching {
+                ignore_case: false,
+                regex: true,
+            },
+            true,
+        )
+        .unwrap();
+        assert_eq!(collected.found, vec![91061, 91062]);
+    }
+
+    #[test]
+    fn test_taxon_name_errors() {
+        let dir = tempdir().unwrap();
+        let report_path = dir.path().join("report.txt");
+        let test_data = "\
+        100.00\t100\t0\tR\t1\troot
+        60.00\t60\t60\tG\t1386\t  Bacillus
+        40.00\t40\t40\tG\t13131\t  Bacillus";
+        std::fs::write(&report_path, test_data).unwrap();
+        let collect = |name: &str, matching: NameMatching| {
+            collect_taxa_to_save(
+                Some(report_path.as_path()),
+                &TaxonExpansion::default(),
+                &[],
+                &[name.to_string()],
+                matching,
+                true,
+            )
+        };
+
+        let err = collect("Bacillus", NameMatching::default()).unwrap_err();
+        assert!(err.to_string().contains("is ambiguous"));
+        assert!(
+            err.to_string()
+                .contains("1386 (G, in root), 13131 (G, in root)")
+        );
+
+        let err = collect("bacillus", NameMatching::default()).unwrap_err();
+        assert!(err.to_string().contains("Did you mean \"Bacillus\"?"));
+
+        let err = collect("Listeria", NameMatching::default()).unwrap_err();
+        assert!(err.to_string().contains("was not found"));
+
+        // a regex may select several taxa
+        let regex = NameMatching {
+            ignore_case: false,
+            regex: true,
+        };
+        let collected = collect("^Bacillus$", regex).unwrap();
+        assert_eq!(collected.found, vec![1386, 13131]);
+        assert!(collect("Bacillus(", regex).is_err());
+    }
+
     #[test]
     fn test_taxon_not_exist() {
         let dir = tempdir().unwrap();
         let report_path = create_test_kraken_report(&dir);
         let taxids = vec![999];
-        let result = collect_taxa_to_save(Some(report_path.as_path()), true, false, &taxids, true);
+        let result = collect_taxa_to_save(
+            Some(report_path.as_path()),
+            &CHILDREN,
+            &taxids,
+            &[],
+            NameMatching::default(),
+            true,
+        );
 
         assert!(result.is_err());
     }
@@ -703,8 +1660,15 @@ mod tests {
         let dir = tempdir().unwrap();
         let report_path = create_test_kraken_report(&dir);
         let taxids = vec![1239, 999];
-        let collected =
-            collect_taxa_to_save(Some(report_path.as_path()), false, false, &taxids, true).unwrap();
+        let collected = collect_taxa_to_save(
+            Some(report_path.as_path()),
+            &TaxonExpansion::default(),
+            &taxids,
+            &[],
+            NameMatching::default(),
+            true,
+        )
+        .unwrap();
 
         assert!(collected.found.contains(&1239));
         assert_eq!(collected.missing, vec![999]);
@@ -713,7 +1677,15 @@ mod tests {
     #[test]
     fn test_dedup_and_sort() {
         let taxids = vec![456, 123, 456, 789, 123];
-        let collected = collect_taxa_to_save(None, false, false, &taxids, true).unwrap();
+        let collected = collect_taxa_to_save(
+            None,
+            &TaxonExpansion::default(),
+            &taxids,
+            &[],
+            NameMatching::default(),
+            true,
+        )
+        .unwrap();
 
         assert_eq!(collected.found, vec![123, 456, 789]);
         assert!(collected.missing.is_empty());
@@ -721,7 +1693,14 @@ mod tests {
 
     #[test]
     fn test_empty_result() {
-        let result = collect_taxa_to_save(None, false, false, &[], true);
+        let result = collect_taxa_to_save(
+            None,
+            &TaxonExpansion::default(),
+            &[],
+            &[],
+            NameMatching::default(),
+            true,
+        );
 
         assert!(result.is_err());
     }
@@ -0,0 +1,853 @@
+use color_eyre::{Result, eyre::eyre};
+use fxhash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize};
+
+use crate::parsers::kraken::{KmerHit, KmerTaxon, KrakenRecord, LcaMap, Tree, parse_lca_map};
+
+/// Read-level filters evaluated against each line of the Kraken2 output.
+#[derive(Debug, Clone, Default)]
+pub struct ReadFilters {
+    pub confidence: Option<ConfidenceFilter>,
+    pub min_clade_fraction: Option<f64>,
+    pub strict_exclusion: Option<StrictExclusion>,
+    pub max_ambiguous_fraction: Option<f64>,
+    /// Apply the k-mer filters to each mate of a paired-end read rather than to the pair as a whole.
+    pub per_mate: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FilterStats {
+    pub reads_below_confidence: usize,
+    pub reads_reassigned: usize,
+    /// Reads rescored with k-mers assigned to taxa missing from the report, which cannot be placed in the tree.
+    pub reads_with_unplaced_kmers: usize,
+    pub unplaced_kmers: u64,
+    pub reads_below_clade_fraction: usize,
+    /// Clade k-mer fractions of the selected reads, when `--min-clade-fraction` is used.
+    pub clade_fractions: FractionDistribution,
+    /// Reads dropped in `--exclude` mode because their final call was an excluded taxon.
+    pub reads_excluded_by_call: usize,
+    /// Reads dropped in `--exclude` mode because of excluded k-mers, despite their final call.
+    pub reads_excluded_by_kmers: usize,
+    pub reads_above_ambiguous_fraction: usize,
+    /// Ambiguous k-mer fractions of the selected reads, when `--max-ambiguous-fraction` is used.
+    pub ambiguous_fractions: FractionDistribution,
+}
+
+impl FilterStats {
+    /// Adds the counts of another set of stats, used to combine the partial results of parser threads.
+    pub fn merge(&mut self, other: &FilterStats) {
+        self.reads_below_confidence += other.reads_below_confidence;
+        self.reads_reassigned += other.reads_reassigned;
+        self.reads_with_unplaced_kmers += other.reads_with_unplaced_kmers;
+        self.unplaced_kmers += other.unplaced_kmers;
+        self.reads_below_clade_fraction += other.reads_below_clade_fraction;
+        self.clade_fractions.merge(&other.clade_fractions);
+        self.reads_excluded_by_call += other.reads_excluded_by_call;
+        self.reads_excluded_by_kmers += other.reads_excluded_by_kmers;
+        self.reads_above_ambiguous_fraction += other.reads_above_ambiguous_fraction;
+        self.ambiguous_fractions.merge(&other.ambiguous_fractions);
+    }
+}
+
+impl ReadFilters {
+    /// Returns the taxon ID to count the read under if it should be extracted, or `None` if it should not.
+    /// Reads are selected on their taxon ID (after any confidence rescoring) and then must pass every
+    /// enabled k-mer filter.
+    pub fn apply(
+        &self,
+        record: &KrakenRecord,
+        taxon_ids: &FxHashSet<i32>,
+        exclude: bool,
+        stats: &mut FilterStats,
+    ) -> Result<Option<i32>> {
+        let mut taxon_id = record.taxon_id;
+        let mut lca_map = None;
+
+        if let Some(confidence) = &self.confidence
+            && taxon_id != 0
+        {
+            let lca_map = parsed_lca_map(&mut lca_map, record.lca_map)?;
+            let Rescore {
+                taxon_id: rescored,
+                unplaced_kmers,
+            } = confidence.rescore(taxon_id, lca_map.hits())?;
+            if unplaced_kmers > 0 {
+                stats.reads_with_unplaced_kmers += 1;
+                stats.unplaced_kmers += unplaced_kmers;
+            }
+            if rescored != taxon_id {
+                stats.reads_below_confidence += 1;
+                if confidence.reassign && rescored != 0 {
+                    stats.reads_reassigned += 1;
+                    taxon_id = rescored;
+                } else {
+                    taxon_id = 0;
+                }
+            }
+        }
+
+        if taxon_ids.contains(&taxon_id) == exclude {
+            if exclude {
+                stats.reads_excluded_by_call += 1;
+            }
+            return Ok(None);
+        }
+
+        if let Some(strict_exclusion) = &self.strict_exclusion
+            && strict_exclusion.has_evidence(
+                parsed_lca_map(&mut lca_map, record.lca_map)?,
+                taxon_ids,
+                self.per_mate,
+            )
+        {
+            stats.reads_excluded_by_kmers += 1;
+            return Ok(None);
+        }
+
+        let mut ambiguous = [None; 2];
+        if let Some(max_fraction) = self.max_ambiguous_fraction {
+            ambiguous = self.fractions(
+                parsed_lca_map(&mut lca_map, record.lca_map)?,
+                ambiguous_fraction,
+            );
+            if ambiguous
+                .iter()
+                .flatten()
+                .any(|&fraction| fraction > max_fraction)
+            {
+                stats.reads_above_ambiguous_fraction += 1;
+                return Ok(None);
+            }
+        }
+
+        let mut clade = [None; 2];
+        if let Some(min_fraction) = self.min_clade_fraction {
+            clade = self.fractions(parsed_lca_map(&mut lca_map, record.lca_map)?, |hits| {
+                clade_fraction(hits, taxon_ids)
+            });
+            if clade
+                .iter()
+                .flatten()
+                .any(|&fraction| fraction < min_fraction)
+            {
+                stats.reads_below_clade_fraction += 1;
+                return Ok(None);
+            }
+        }
+
+        for &fraction in ambiguous.iter().flatten() {
+            stats.ambiguous_fractions.add(fraction);
+        }
+        for &fraction in clade.iter().flatten() {
+            stats.clade_fractions.add(fraction);
+        }
+
+        Ok(Some(taxon_id))
+    }
+
+    // one fraction per mate in per-mate mode, otherwise one for the read as a whole. Mates without any k-mers,
+    // too short to be classified, are skipped rather than failing the pair, unless neither mate has k-mers
+    fn fractions(
+        &self,
+        lca_map: &LcaMap,
+        fraction: impl Fn(&[KmerHit]) -> f64,
+    ) -> [Option<f64>; 2] {
+        let mut fractions = [None; 2];
+        if self.per_mate {
+            for (slot, hits) in fractions.iter_mut().zip(lca_map.mates()) {
+                *slot = has_kmers(hits).then(|| fraction(hits));
+            }
+        }
+        if fractions == [None; 2] {
+            fractions[0] = Some(fraction(lca_map.hits()));
+        }
+        fractions
+    }
+}
+
+// parses the LCA map on first use, so reads are only parsed once however many filters need it
+fn parsed_lca_map<'a>(parsed: &'a mut Option<LcaMap>, lca_map: &[u8]) -> Result<&'a LcaMap> {
+    if parsed.is_none() {
+        *parsed = Some(parse_lca_map(lca_map)?);
+    }
+    Ok(parsed.get_or_insert_default())
+}
+
+fn has_kmers(hits: &[KmerHit]) -> bool {
+    hits.iter().any(|hit| hit.count > 0)
+}
+
+/// Fraction of a read's non-ambiguous k-mers assigned within `clade`. K-mers assigned to taxon 0 count
+/// towards the total.
+pub fn clade_fraction(hits: &[KmerHit], clade: &FxHashSet<i32>) -> f64 {
+    let mut clade_kmers = 0u64;
+    let mut total_kmers = 0u64;
+    for hit in hits {
+        if let KmerTaxon::Taxon(taxon_id) = hit.taxon {
+            total_kmers += u64::from(hit.count);
+            if clade.contains(&taxon_id) {
+                clade_kmers += u64::from(hit.count);
+            }
+        }
+    }
+
+    if total_kmers == 0 {
+        0.0
+    } else {
+        clade_kmers as f64 / total_kmers as f64
+    }
+}
+
+/// Fraction of all of a read's k-mers that are ambiguous (`A:` entries). Reads with no k-mers at all are
+/// treated as fully ambiguous.
+pub fn ambiguous_fraction(hits: &[KmerHit]) -> f64 {
+    let mut ambiguous_kmers = 0u64;
+    let mut total_kmers = 0u64;
+    for hit in hits {
+        total_kmers += u64::from(hit.count);
+        if hit.taxon == KmerTaxon::Ambiguous {
+            ambiguous_kmers += u64::from(hit.count);
+        }
+    }
+
+    if total_kmers == 0 {
+        1.0
+    } else {
+        ambiguous_kmers as f64 / total_kmers as f64
+    }
+}
+
+/// Rejects reads carrying k-mer evidence for the excluded taxa, whatever their final call.
+#[derive(Debug, Clone, Copy)]
+pub struct StrictExclusion {
+    pub min_kmers: Option<u32>,
+    pub min_run: Option<u32>,
+}
+
+impl StrictExclusion {
+    /// Returns true if at least `min_kmers` k-mers, or a run of at least `min_run` consecutive k-mers, were
+    /// assigned within `clade`. Runs never span the two mates of a pair, and in per-mate mode k-mers are
+    /// also counted for each mate separately.
+    pub fn has_evidence(&self, lca_map: &LcaMap, clade: &FxHashSet<i32>, per_mate: bool) -> bool {
+        let mut clade_kmers = 0u32;
+        for hits in lca_map.mates() {
+            if per_mate {
+                clade_kmers = 0;
+            }
+            let mut run = 0u32;
+            for hit in hits {
+                match hit.taxon {
+                    KmerTaxon::Taxon(taxon_id) if clade.contains(&taxon_id) => {
+                        clade_kmers += hit.count;
+                        run += hit.count;
+                    }
+                    _ => run = 0,
+                }
+                if self
+                    .min_kmers
+                    .is_some_and(|min_kmers| clade_kmers >= min_kmers)
+                    || self.min_run.is_some_and(|min_run| run >= min_run)
+                {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Running distribution of a per-read fraction, binned into ten equal-width bins between 0 and 1.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FractionDistribution {
+    reads: usize,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+    histogram: [usize; 10],
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FractionSummary {
+    pub reads: usize,
+    pub mean: Option<f64>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub histogram: [usize; 10],
+}
+
+impl FractionDistribution {
+    pub fn add(&mut self, fraction: f64) {
+        self.reads += 1;
+        self.sum += fraction;
+        self.min = Some(self.min.map_or(fraction, |min| min.min(fraction)));
+        self.max = Some(self.max.map_or(fraction, |max| max.max(fraction)));
+        let bin = ((fraction * 10.0) as usize).min(9);
+        self.histogram[bin] += 1;
+    }
+
+    pub fn merge(&mut self, other: &FractionDistribution) {
+        self.reads += other.reads;
+        self.sum += other.sum;
+        self.min = match (self.min, other.min) {
+            (Some(min), Some(other)) => Some(min.min(other)),
+            (min, other) => min.or(other),
+        };
+        self.max = match (self.max, other.max) {
+            (Some(max), Some(other)) => Some(max.max(other)),
+            (max, other) => max.or(other),
+        };
+        for (bin, other) in self.histogram.iter_mut().zip(other.histogram) {
+            *bin += other;
+        }
+    }
+
+    pub fn summary(&self) -> FractionSummary {
+        FractionSummary {
+            reads: self.reads,
+            mean: (self.reads > 0).then(|| self.sum / self.reads as f64),
+            min: self.min,
+            max: self.max,
+            histogram: self.histogram,
+        }
+    }
+}
+
+/// Result of rescoring a single read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rescore {
+    /// Taxon the read is called as, or 0 if no ancestor meets the threshold.
+    pub taxon_id: i32,
+    /// K-mers assigned to taxa missing from the report, which only count towards the total.
+    pub unplaced_kmers: u64,
+}
+
+/// Recomputes Kraken2's confidence score for a read against the report's tree.
+#[derive(Debug, Clone)]
+pub struct ConfidenceFilter {
+    threshold: f64,
+    reassign: bool,
+    nodes: Vec<Tree>,
+    // taxonid -> index in the nodes vector, for every taxon in the report
+    node_index: FxHashMap<i32, usize>,
+}
+
+impl ConfidenceFilter {
+    pub fn new(threshold: f64, reassign: bool, nodes: Vec<Tree>) -> Self {
+        let node_index = nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| (node.taxon_id, index))
+            .collect();
+        Self {
+            threshold,
+            reassign,
+            nodes,
+            node_index,
+        }
+    }
+
+    /// Returns the first taxon, starting at the read's original call and climbing towards the root, whose
+    /// clade holds at least `threshold` of the read's k-mers, or 0 if no ancestor meets the threshold.
+    ///
+    /// Kraken2 climbs from its highest scoring taxon until the threshold is met, so for any threshold at or
+    /// above the one used for classification, continuing the climb from the original call gives the same
+    /// answer as Kraken2 would. As in Kraken2, ambiguous k-mers count towards the total. K-mers assigned to
+    /// taxa missing from the report cannot be placed in the tree and only count towards the total; they are
+    /// returned alongside the call so that they can be reported.
+    pub fn rescore(&self, taxon_id: i32, hits: &[KmerHit]) -> Result<Rescore> {
+        let &start_index = self
+            .node_index
+            .get(&taxon_id)
+            .ok_or_else(|| eyre!("taxon ID {taxon_id} is not present in the kraken report"))?;
+
+        let mut lineage = Vec::new();
+        let mut curr_index = Some(start_index);
+        while let Some(index) = curr_index {
+            lineage.push(index);
+            curr_index = self.nodes[index].parent;
+        }
+
+        // k-mers are credited to the lowest node of the lineage their taxon falls under, so that the
+        // running sum up the lineage gives the clade score of each ancestor
+        let mut lineage_hits = vec![0u64; lineage.len()];
+        let mut total_kmers = 0u64;
+        let mut unplaced_kmers = 0u64;
+        for hit in hits {
+            total_kmers += u64::from(hit.count);
+            let KmerTaxon::Taxon(hit_taxon) = hit.taxon else {
+                continue;
+            };
+            let mut curr_index = self.node_index.get(&hit_taxon).copied();
+            if curr_index.is_none() && hit_taxon != 0 {
+                unplaced_kmers += u64::from(hit.count);
+            }
+            while let Some(index) = curr_index {
+                if let Some(position) = lineage.iter().position(|&node| node == index) {
+                    lineage_hits[position] += u64::from(hit.count);
+                    break;
+                }
+                curr_index = self.nodes[index].parent;
+            }
+        }
+
+        let required_kmers = (self.threshold * total_kmers as f64).ceil() as u64;
+        let mut clade_kmers = 0;
+        let mut rescore = Rescore {
+            taxon_id: 0,
+            unplaced_kmers,
+        };
+        for (position, &kmers) in lineage_hits.iter().enumerate() {
+            clade_kmers += kmers;
+            if clade_kmers >= required_kmers {
+                rescore.taxon_id = self.nodes[lineage[position]].taxon_id;
+                break;
+            }
+        }
+
+        Ok(rescore)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::kraken::SequenceLength;
+
+    // root(1) -> Bacteria(2) -> Enterobacterales(91347) -> {E. coli(562), Salmonella(590)}
+    fn test_nodes() -> Vec<Tree> {
+        let mut nodes = vec![
+            Tree::new(1, 0, None),
+            Tree::new(2, 1, Some(0)),
+            Tree::new(91347, 2, Some(1)),
+            Tree::new(562, 3, Some(2)),
+            Tree::new(590, 3, Some(2)),
+        ];
+        nodes[0].children = vec![1];
+        nodes[1].children = vec![2];
+        nodes[2].children = vec![3, 4];
+        nodes
+    }
+
+    fn record(taxon_id: i32, lca_map: &str) -> KrakenRecord<'_> {
+        KrakenRecord {
+            is_classified: taxon_id != 0,
+            read_id: b"read_1",
+            taxon_id,
+            taxon_name: None,
+            length: SequenceLength::Single(150),
+            lca_map: lca_map.as_bytes(),
+        }
+    }
+
+    #[test]
+    fn test_rescore_passes_at_original_call() {
+        let filter = ConfidenceFilter::new(0.5, false, test_nodes());
+        let lca_map = parse_lca_map(b"562:6 590:2 0:2").unwrap();
+        assert_eq!(filter.rescore(562, lca_map.hits()).unwrap().taxon_id, 562);
+    }
+
+    #[test]
+    fn test_rescore_climbs_to_ancestor() {
+        let filter = ConfidenceFilter::new(0.7, false, test_nodes());
+        let lca_map = parse_lca_map(b"562:5 590:3 0:2").unwrap();
+        assert_eq!(filter.rescore(562, lca_map.hits()).unwrap().taxon_id, 91347);
+    }
+
+    #[test]
+    fn test_rescore_counts_ambiguous_in_total() {
+        let filter = ConfidenceFilter::new(0.5, false, test_nodes());
+        let lca_map = parse_lca_map(b"562:4 A:6").unwrap();
+        assert_eq!(filter.rescore(562, lca_map.hits()).unwrap().taxon_id, 0);
+        let lca_map = parse_lca_map(b"562:5 A:5").unwrap();
+        assert_eq!(filter.rescore(562, lca_map.hits()).unwrap().taxon_id, 562);
+    }
+
+    #[test]
+    fn test_rescore_runs_off_tree() {
+        let filter = ConfidenceFilter::new(0.9, false, test_nodes());
+        let lca_map = parse_lca_map(b"562:2 0:8").unwrap();
+        assert_eq!(filter.rescore(562, lca_map.hits()).unwrap().taxon_id, 0);
+    }
+
+    #[test]
+    fn test_rescore_counts_unplaced_kmers() {
+        let filter = ConfidenceFilter::new(0.5, false, test_nodes());
+        let lca_map = parse_lca_map(b"562:4 9606:3 0:2 A:1").unwrap();
+        assert_eq!(
+            filter.rescore(562, lca_map.hits()).unwrap(),
+            Rescore {
+                taxon_id: 0,
+                unplaced_kmers: 3
+            }
+        );
+    }
+
+    #[test]
+    fn test_rescore_taxon_not_in_report() {
+        let filter = ConfidenceFilter::new(0.5, false, test_nodes());
+        let lca_map = parse_lca_map(b"9606:10").unwrap();
+        assert!(filter.rescore(9606, lca_map.hits()).is_err());
+    }
+
+    fn taxa(taxon_ids: &[i32]) -> FxHashSet<i32> {
+        taxon_ids.iter().copied().collect()
+    }
+
+    #[test]
+    fn test_apply_below_confidence_unclassified() {
+        let filters = ReadFilters {
+            confidence: Some(ConfidenceFilter::new(0.7, false, test_nodes())),
+            ..Default::default()
+        };
+        let mut stats = FilterStats::default();
+        let taxon_id = filters
+            .apply(
+                &record(562, "562:5 590:3 0:2"),
+                &taxa(&[0]),
+                false,
+                &mut stats,
+            )
+            .unwrap();
+        assert_eq!(taxon_id, Some(0));
+        assert_eq!(stats.reads_below_confidence, 1);
+        assert_eq!(stats.reads_reassigned, 0);
+        assert_eq!(stats.reads_with_unplaced_kmers, 0);
+
+        filters
+            .apply(
+                &record(562, "562:5 9606:2 0:3"),
+                &taxa(&[0]),
+                false,
+                &mut stats,
+            )
+            .unwrap();
+        assert_eq!(stats.reads_with_unplaced_kmers, 1);
+        assert_eq!(stats.unplaced_kmers, 2);
+    }
+
+    #[test]
+    fn test_apply_below_confidence_reassigned() {
+        let filters = ReadFilters {
+            confidence: Some(ConfidenceFilter::new(0.7, true, test_nodes())),
+            ..Default::default()
+        };
+        let mut stats = FilterStats::default();
+        let taxon_id = filters
+            .apply(
+                &record(562, "562:5 590:3 0:2"),
+                &taxa(&[562]),
+                false,
+                &mut stats,
+            )
+            .unwrap();
+        assert_eq!(taxon_id, None);
+        let taxon_id = filters
+            .apply(
+                &record(562, "562:5 590:3 0:2"),
+                &taxa(&[91347]),
+                false,
+                &mut stats,
+            )
+            .unwrap();
+        assert_eq!(taxon_id, Some(91347));
+        assert_eq!(stats.reads_below_confidence, 2);
+        assert_eq!(stats.reads_reassigned, 2);
+    }
+
+    #[test]
+    fn test_apply_no_filters() {
+        let filters = ReadFilters::default();
+        let mut stats = FilterStats::default();
+        let read = record(562, "0:10");
+        assert_eq!(
+            filters
+                .apply(&read, &taxa(&[562]), false, &mut stats)
+                .unwrap(),
+            Some(562)
+        );
+        assert_eq!(
+            filters
+                .apply(&read, &taxa(&[2]), false, &mut stats)
+                .unwrap(),
+            None
+        );
+        assert_eq!(stats, FilterStats::default());
+        assert_eq!(
+            filters
+                .apply(&read, &taxa(&[562]), true, &mut stats)
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            filters.apply(&read, &taxa(&[2]), true, &mut stats).unwrap(),
+            Some(562)
+        );
+        assert_eq!(stats.reads_excluded_by_call, 1);
+    }
+
+    #[test]
+    fn test_clade_fraction_ignores_ambiguous() {
+        let lca_map = parse_lca_map(b"562:3 590:3 2:2 0:2 A:10").unwrap();
+        assert_eq!(clade_fraction(lca_map.hits(), &taxa(&[562, 590])), 0.6);
+        let lca_map = parse_lca_map(b"A:10").unwrap();
+        assert_eq!(clade_fraction(lca_map.hits(), &taxa(&[562, 590])), 0.0);
+    }
+
+    #[test]
+    fn test_apply_clade_fraction() {
+        let filters = ReadFilters {
+            min_clade_fraction: Some(0.5),
+            ..Default::default()
+        };
+        let clade = taxa(&[562]);
+        let mut stats = FilterStats::default();
+        assert_eq!(
+            filters
+                .apply(&record(562, "562:8 0:2"), &clade, false, &mut stats)
+                .unwrap(),
+            Some(562)
+        );
+        assert_eq!(
+            filters
+                .apply(&record(562, "562:2 2:6 0:2"), &clade, false, &mut stats)
+                .unwrap(),
+            None
+        );
+        // reads outside the clade are not selected, so are not counted
+        assert_eq!(
+            filters
+                .apply(&record(2, "2:10"), &clade, false, &mut stats)
+                .unwrap(),
+            None
+        );
+        assert_eq!(stats.reads_below_clade_fraction, 1);
+        let summary = stats.clade_fractions.summary();
+        assert_eq!(summary.reads, 1);
+        assert_eq!(summary.mean, Some(0.8));
+        assert_eq!(summary.histogram[8], 1);
+    }
+
+    #[test]
+    fn test_strict_exclusion_evidence() {
+        let clade = taxa(&[9606, 9605]);
+        let strict_exclusion = StrictExclusion {
+            min_kmers: Some(10),
+            min_run: None,
+        };
+        assert!(strict_exclusion.has_evidence(
+            &parse_lca_map(b"9606:4 0:20 9605:6").unwrap(),
+            &clade,
+            false
+        ));
+        assert!(!strict_exclusion.has_evidence(
+            &parse_lca_map(b"9606:4 0:20 9605:5").unwrap(),
+            &clade,
+            false
+        ));
+
+        let strict_exclusion = StrictExclusion {
+            min_kmers: None,
+            min_run: Some(6),
+        };
+        assert!(strict_exclusion.has_evidence(
+            &parse_lca_map(b"0:5 9606:4 9605:2 0:5").unwrap(),
+            &clade,
+            false
+        ));
+        assert!(!strict_exclusion.has_evidence(
+            &parse_lca_map(b"9606:4 A:1 9605:2 1:5").unwrap(),
+            &clade,
+            false
+        ));
+    }
+
+    #[test]
+    fn test_apply_strict_exclusion() {
+        let filters = ReadFilters {
+            strict_exclusion: Some(StrictExclusion {
+                min_kmers: Some(5),
+                min_run: None,
+            }),
+            ..Default::default()
+        };
+        let excluded = taxa(&[9606]);
+        let mut stats = FilterStats::default();
+        assert_eq!(
+            filters
+                .apply(&record(1, "9606:5 1:2 0:10"), &excluded, true, &mut stats)
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            filters
+                .apply(&record(0, "9606:4 0:20"), &excluded, true, &mut stats)
+                .unwrap(),
+            Some(0)
+        );
+        assert_eq!(
+            filters
+                .apply(&record(9606, "9606:10"), &excluded, true, &mut stats)
+                .unwrap(),
+            None
+        );
+        assert_eq!(stats.reads_excluded_by_kmers, 1);
+        assert_eq!(stats.reads_excluded_by_call, 1);
+    }
+
+    #[test]
+    fn test_ambiguous_fraction() {
+        assert_eq!(
+            ambiguous_fraction(parse_lca_map(b"562:3 A:6 0:1").unwrap().hits()),
+            0.6
+        );
+        assert_eq!(
+            ambiguous_fraction(parse_lca_map(b"562:10").unwrap().hits()),
+            0.0
+        );
+        assert_eq!(ambiguous_fraction(&[]), 1.0);
+    }
+
+    #[test]
+    fn test_apply_max_ambiguous_fraction() {
+        let filters = ReadFilters {
+            max_ambiguous_fraction: Some(0.5),
+            ..Default::default()
+        };
+        let clade = taxa(&[562]);
+        let mut stats = FilterStats::default();
+        assert_eq!(
+            filters
+                .apply(&record(562, "562:5 A:5"), &clade, false, &mut stats)
+                .unwrap(),
+            Some(562)
+        );
+        assert_eq!(
+            filters
+                .apply(&record(562, "562:4 A:6"), &clade, false, &mut stats)
+                .unwrap(),
+            None
+        );
+        assert_eq!(stats.reads_above_ambiguous_fraction, 1);
+        let summary = stats.ambiguous_fractions.summary();
+        assert_eq!(summary.reads, 1);
+        assert_eq!(summary.max, Some(0.5));
+    }
+
+    #[test]
+    fn test_strict_exclusion_paired() {
+        let clade = taxa(&[9606]);
+        let strict_exclusion = StrictExclusion {
+            min_kmers: None,
+            min_run: Some(6),
+        };
+        let lca_map = parse_lca_map(b"0:2 9606:3 |:| 9606:3 0:2").unwrap();
+        assert!(!strict_exclusion.has_evidence(&lca_map, &clade, false));
+
+        let strict_exclusion = StrictExclusion {
+            min_kmers: Some(6),
+            min_run: None,
+        };
+        assert!(strict_exclusion.has_evidence(&lca_map, &clade, false));
+        assert!(!strict_exclusion.has_evidence(&lca_map, &clade, true));
+    }
+
+    #[test]
+    fn test_apply_per_mate() {
+        let clade = taxa(&[562]);
+        // the pair as a whole passes, but the second mate on its own does not
+        let read = record(562, "562:10 |:| 562:2 2:6 A:2");
+        let mut filters = ReadFilters {
+            min_clade_fraction: Some(0.6),
+            ..Default::default()
+        };
+        let mut stats = FilterStats::default();
+        assert_eq!(
+            filters.apply(&read, &clade, false, &mut stats).unwrap(),
+            Some(562)
+        );
+        filters.per_mate = true;
+        assert_eq!(
+            filters.apply(&read, &clade, false, &mut stats).unwrap(),
+            None
+        );
+
+        let mut filters = ReadFilters {
+            max_ambiguous_fraction: Some(0.15),
+            ..Default::default()
+        };
+        assert_eq!(
+            filters.apply(&read, &clade, false, &mut stats).unwrap(),
+            Some(562)
+        );
+        filters.per_mate = true;
+        assert_eq!(
+            filters.apply(&read, &clade, false, &mut stats).unwrap(),
+            None
+        );
+        assert_eq!(stats.reads_below_clade_fraction, 1);
+        assert_eq!(stats.reads_above_ambiguous_fraction, 1);
+    }
+
+    #[test]
+    fn test_apply_per_mate_without_kmers() {
+        let clade = taxa(&[562]);
+        let filters = ReadFilters {
+            min_clade_fraction: Some(0.6),
+            max_ambiguous_fraction: Some(0.15),
+            per_mate: true,
+            ..Default::default()
+        };
+        let mut stats = FilterStats::default();
+        // a mate too short to have any k-mers is skipped rather than failing the pair
+        assert_eq!(
+            filters
+                .apply(&record(562, "562:10 |:| "), &clade, false, &mut stats)
+                .unwrap(),
+            Some(562)
+        );
+        assert_eq!(stats.clade_fractions.summary().reads, 1);
+        // unless neither mate has any, when the pair is judged as a whole
+        assert_eq!(
+            filters
+                .apply(&record(562, " |:| "), &clade, false, &mut stats)
+                .unwrap(),
+            None
+        );
+        assert_eq!(stats.reads_above_ambiguous_fraction, 1);
+    }
+
+    #[test]
+    fn test_fraction_distribution_merge() {
+        let mut first = FractionDistribution::default();
+        first.add(0.2);
+        first.add(0.4);
+        let mut second = FractionDistribution::default();
+        second.add(0.9);
+        let mut merged = FractionDistribution::default();
+        merged.merge(&first);
+        merged.merge(&second);
+        merged.merge(&FractionDistribution::default());
+
+        let mut sequential = FractionDistribution::default();
+        for fraction in [0.2, 0.4, 0.9] {
+            sequential.add(fraction);
+        }
+        assert_eq!(merged, sequential);
+    }
+
+    #[test]
+    fn test_fraction_distribution() {
+        let mut distribution = FractionDistribution::default();
+        assert_eq!(distribution.summary().mean, None);
+        distribution.add(0.0);
+        distribution.add(0.5);
+        distribution.add(1.0);
+        let summary = distribution.summary();
+        assert_eq!(summary.reads, 3);
+        assert_eq!(summary.mean, Some(0.5));
+        assert_eq!(summary.min, Some(0.0));
+        assert_eq!(summary.max, Some(1.0));
+        assert_eq!(summary.histogram, [1, 0, 0, 0, 0, 1, 0, 0, 0, 1]);
+    }
+}
@@ -1,62 +1,158 @@
+use std::{fs, num::NonZeroUsize, path::PathBuf};
+
 use color_eyre::{
     Result,
-    eyre::{bail, ensure},
+    eyre::{WrapErr, bail, ensure, eyre},
 };
-use fxhash::{FxHashMap, FxHashSet};
-use log::info;
+use fxhash::FxHashMap;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    Cli,
-    extract::{self, KractorResult, process_paired_end, process_single_end},
-    parsers::{self, kraken::ProcessedKrakenOutput},
+    classify::Kraken2Run,
+    cli::{ClassifyArgs, ExtractArgs},
+    extract::{
+        self, KractorResult, NameMatching, OrphanCounts, ResolvedTaxonName, TaxonExpansion,
+        TaxonOrigin, process_interleaved, process_ordered_interleaved, process_ordered_paired_end,
+        process_ordered_single_end, process_paired_end, process_single_end,
+    },
+    filter::{ConfidenceFilter, FilterStats, FractionSummary, ReadFilters, StrictExclusion},
+    parsers::{
+        self,
+        fastx::MateSync,
+        kraken::{KrakenSource, OrderedKrakenOutput, ProcessedKrakenOutput, Tree},
+    },
+    read_ids::ReadSelection,
 };
 
-pub fn run(args: Cli) -> Result<()> {
-    Kractor::new(args).run_inner()
+pub fn run(args: ExtractArgs, kraken: PathBuf, report: Option<PathBuf>) -> Result<()> {
+    let mut kractor = Kractor::new(args, KrakenSource::Path(kraken), report);
+    kractor.extract()?;
+    kractor.output_summary()
+}
+
+/// Runs Kraken2 on the input and extracts reads from its output. Unless the report is needed to choose the taxa to
+/// extract, the output is streamed straight from Kraken2 and never written to disk.
+pub fn classify_and_extract(mut args: ClassifyArgs) -> Result<()> {
+    if args.extract.threads.get() > 1 || args.extract.max_memory.is_some() {
+        warn!(
+            "--threads and --max-memory have no effect with classify-and-extract, as the Kraken2 output is read in order"
+        );
+    }
+    args.extract.ordered = true;
+    args.extract.threads = NonZeroUsize::MIN;
+    args.extract.max_memory = None;
+
+    // Kraken2 writes its report once every read has been classified
+    let needs_report = args.extract.needs_report();
+    let mut kraken2 = Kraken2Run::spawn(&args, !needs_report)?;
+    let (kraken, report) = if needs_report {
+        info!(
+            "Waiting for Kraken2 to finish, as its report is needed to choose the reads to extract"
+        );
+        let output = kraken2.wait_for_output()?;
+        (
+            KrakenSource::Path(output),
+            Some(kraken2.report().to_path_buf()),
+        )
+    } else {
+        let stdout = kraken2
+            .take_stdout()
+            .ok_or_else(|| eyre!("Kraken2 standard output was not captured"))?;
+        (KrakenSource::Process(stdout), None)
+    };
+    let kraken2_summary = Kraken2Summary {
+        command: kraken2.command().to_vec(),
+        report: args.kraken2_report.clone(),
+    };
+
+    let mut kractor = Kractor::new(args.extract, kraken, report);
+    let result = kractor.extract();
+    kraken2.finish(result)?;
+    if let Some(summary) = &mut kractor.summary {
+        summary.kraken2 = Some(kraken2_summary);
+    }
+    kractor.output_summary()
 }
 
 struct Kractor {
-    args: Cli,
+    args: ExtractArgs,
+    // taken when the Kraken2 output is read in order
+    kraken: Option<KrakenSource>,
+    report: Option<PathBuf>,
     taxon_ids: Vec<i32>,
     missing_taxon_ids: Vec<i32>,
-    reads_to_save: FxHashSet<Vec<u8>>,
+    tree_nodes: Vec<Tree>,
+    taxon_origins: FxHashMap<i32, TaxonOrigin>,
+    resolved_names: Vec<ResolvedTaxonName>,
+    // built while the report's tree is at hand, as --confidence takes it over to rescore reads
+    parents: Option<ParentsSummary>,
+    reads_to_save: ReadSelection,
     reads_per_taxon: FxHashMap<i32, usize>,
+    taxon_names: FxHashMap<i32, String>,
+    filter_stats: FilterStats,
+    orphans: OrphanCounts,
     summary: Option<Summary>,
 }
 
 impl Kractor {
-    fn new(args: Cli) -> Self {
+    fn new(args: ExtractArgs, kraken: KrakenSource, report: Option<PathBuf>) -> Self {
         Self {
             args,
+            kraken: Some(kraken),
+            report,
             taxon_ids: Vec::new(),
             missing_taxon_ids: Vec::new(),
-            reads_to_save: FxHashSet::default(),
+            tree_nodes: Vec::new(),
+            taxon_origins: FxHashMap::default(),
+            resolved_names: Vec::new(),
+            parents: None,
+            reads_to_save: ReadSelection::default(),
             reads_per_taxon: FxHashMap::default(),
+            taxon_names: FxHashMap::default(),
+            filter_stats: FilterStats::default(),
+            orphans: OrphanCounts::default(),
             summary: None,
         }
     }
 
-    fn run_inner(&mut self) -> Result<()> {
+    fn extract(&mut self) -> Result<()> {
         info!(
             "Starting kractor at {}",
             chrono::Local::now().format("%H:%M:%S")
         );
         self.validate_outputs()?;
         self.collect_taxa()?;
-        info!("Processing Kraken2 output file");
-        self.process_kraken_output()?;
-        info!("Processing reads");
-        self.process_reads()?;
+        let result = if self.args.ordered {
+            info!("Processing Kraken2 output and reads in order");
+            self.process_ordered()?
+        } else {
+            if self
+                .kraken
+                .as_ref()
+                .and_then(KrakenSource::path)
+                .is_some_and(parsers::kraken::is_kraken_pipe)
+            {
+                info!(
+                    "The Kraken2 output is read from a pipe, add --ordered to extract reads as it arrives rather than after it has been read in full"
+                );
+            }
+            info!("Processing Kraken2 output file");
+            self.process_kraken_output()?;
+            info!("Processing reads");
+            let result = self.process_reads()?;
+            self.check_unknown_reads(&result)?;
+            result
+        };
+        self.summarise(result);
         info!("Complete at {}", chrono::Local::now().format("%H:%M:%S"));
-        self.output_summary()?;
         Ok(())
     }
 
     fn validate_outputs(&self) -> Result<()> {
-        for out_file in &self.args.output {
+        for out_file in self.args.output.iter().chain(&self.args.orphan_output) {
             ensure!(
-                !out_file.exists(),
+                parsers::fastx::is_stdout(out_file) || !out_file.exists(),
                 "Output file already exists: {}",
                 out_file.display()
             );
@@ -65,29 +161,87 @@ impl Kractor {
     }
 
     fn collect_taxa(&mut self) -> Result<()> {
+        let expansion = TaxonExpansion {
+            children: self.args.children,
+            parents: self.args.parents,
+            parents_up_to: self.args.parents_up_to,
+            clade_rank: self.args.clade_rank,
+            min_rank: self.args.min_rank,
+            max_rank: self.args.max_rank,
+        };
+        let name_matching = NameMatching {
+            ignore_case: self.args.ignore_case,
+            regex: self.args.name_regex,
+        };
         let collected = extract::collect_taxa_to_save(
-            self.args.report.as_deref(),
-            self.args.children,
-            self.args.parents,
+            self.report.as_deref(),
+            &expansion,
             &self.args.taxid,
+            &self.args.taxon_name,
+            name_matching,
             !self.args.no_report_header_detect,
         )?;
         self.taxon_ids = collected.found;
         self.missing_taxon_ids = collected.missing;
+        self.tree_nodes = collected.nodes;
+        self.taxon_origins = collected.origins;
+        self.resolved_names = collected.resolved_names;
+        self.parents = self.args.parents.then(|| self.parents_summary());
         Ok(())
     }
 
+    fn read_filters(&mut self) -> ReadFilters {
+        let confidence = self.args.confidence.map(|threshold| {
+            ConfidenceFilter::new(
+                threshold,
+                self.args.reassign,
+                std::mem::take(&mut self.tree_nodes),
+            )
+        });
+        let strict_exclusion = (self.args.strict_exclude_kmers.is_some()
+            || self.args.strict_exclude_run.is_some())
+        .then_some(StrictExclusion {
+            min_kmers: self.args.strict_exclude_kmers,
+            min_run: self.args.strict_exclude_run,
+        });
+        ReadFilters {
+            confidence,
+            min_clade_fraction: self.args.min_clade_fraction,
+            strict_exclusion,
+            max_ambiguous_fraction: self.args.max_ambiguous_fraction,
+            per_mate: self.args.per_mate,
+        }
+    }
+
     fn process_kraken_output(&mut self) -> Result<()> {
+        let kraken_path = self
+            .kraken
+            .as_ref()
+            .and_then(KrakenSource::path)
+            .ok_or_else(|| eyre!("The output of a running Kraken2 can only be read in order"))?
+            .to_path_buf();
+        let filters = self.read_filters();
         let ProcessedKrakenOutput {
             reads_to_save,
             reads_per_taxon,
+            taxon_names,
+            filter_stats,
         } = parsers::kraken::process_kraken_output(
-            &self.args.kraken,
+            &kraken_path,
             self.args.exclude,
             &self.taxon_ids,
+            &filters,
+            self.args.threads.get(),
+            // paired inputs are each read against the selection at the same time
+            self.args
+                .max_memory
+                .map(|max_memory| max_memory / self.args.input.len()),
         )?;
         self.reads_to_save = reads_to_save;
         self.reads_per_taxon = reads_per_taxon;
+        self.taxon_names = taxon_names;
+        self.filter_stats = filter_stats;
+        self.log_filter_stats();
 
         if self.reads_to_save.is_empty() {
             bail!("No reads found for the specified taxon ID(s). Nothing to extract.");
@@ -97,27 +251,143 @@ impl Kractor {
         Ok(())
     }
 
-    fn process_reads(&mut self) -> Result<()> {
-        let paired = self.args.input.len() == 2;
-        let input_layout = if paired { "paired" } else { "single" };
-        let reads_extracted_per_taxon = self.get_reads_extracted_per_taxon();
+    fn log_filter_stats(&self) {
+        if self.filter_stats.reads_below_confidence > 0 {
+            info!(
+                "{} reads did not meet the confidence threshold ({} reassigned to an ancestor)",
+                self.filter_stats.reads_below_confidence, self.filter_stats.reads_reassigned
+            );
+        }
+        if self.filter_stats.reads_with_unplaced_kmers > 0 {
+            warn!(
+                "{} k-mers in {} reads were assigned to taxa missing from the report and could not be placed \
+                 in the tree when rescoring, rerun Kraken2 with --report-zero-counts to include every taxon",
+                self.filter_stats.unplaced_kmers, self.filter_stats.reads_with_unplaced_kmers
+            );
+        }
+        if self.filter_stats.reads_below_clade_fraction > 0 {
+            info!(
+                "{} reads did not meet the minimum clade k-mer fraction",
+                self.filter_stats.reads_below_clade_fraction
+            );
+        }
+        if self.filter_stats.reads_above_ambiguous_fraction > 0 {
+            info!(
+                "{} reads exceeded the maximum ambiguous k-mer fraction",
+                self.filter_stats.reads_above_ambiguous_fraction
+            );
+        }
+        if self.filter_stats.reads_excluded_by_kmers > 0 {
+            info!(
+                "{} reads excluded by their k-mers in addition to {} excluded by their final call",
+                self.filter_stats.reads_excluded_by_kmers, self.filter_stats.reads_excluded_by_call
+            );
+        }
+    }
+
+    // decides each read from its line of the Kraken2 output as the input is read, without collecting read IDs
+    fn process_ordered(&mut self) -> Result<KractorResult> {
+        let filters = self.read_filters();
+        let reader = self
+            .kraken
+            .take()
+            .ok_or_else(|| eyre!("The Kraken2 output has already been read"))?
+            .open()?;
+        let mut kraken =
+            OrderedKrakenOutput::new(reader, self.args.exclude, &self.taxon_ids, &filters);
 
-        let result = if paired {
-            let (r1, r2) = process_paired_end(
+        let result = if self.args.interleaved {
+            process_ordered_interleaved(
+                &mut kraken,
+                &self.args.input,
+                &self.args.output,
+                self.args.output_type,
+                self.args.compression_level,
+                self.args.output_format,
+            )?
+        } else if self.args.input.len() == 2 {
+            process_ordered_paired_end(
+                &mut kraken,
+                &self.args.input,
+                &self.args.output,
+                self.args.output_type,
+                self.args.compression_level,
+                self.args.output_format,
+            )?
+        } else {
+            process_ordered_single_end(
+                &mut kraken,
+                &self.args.input,
+                &self.args.output,
+                self.args.output_type,
+                self.args.compression_level,
+                self.args.output_format,
+            )?
+        };
+
+        let ProcessedKrakenOutput {
+            reads_per_taxon,
+            taxon_names,
+            filter_stats,
+            ..
+        } = kraken.finish();
+        self.reads_per_taxon = reads_per_taxon;
+        self.taxon_names = taxon_names;
+        self.filter_stats = filter_stats;
+        self.log_filter_stats();
+
+        if result.reads_output == 0 {
+            warn!("No reads found for the specified taxon ID(s), the output is empty");
+        }
+        Ok(result)
+    }
+
+    fn process_reads(&mut self) -> Result<KractorResult> {
+        let passes = self.reads_to_save.passes();
+        if passes > 1
+            && let Some(stream) = self
+                .args
+                .input
+                .iter()
+                .find(|path| parsers::fastx::is_fastx_stream(path))
+        {
+            bail!(
+                "The read IDs exceed --max-memory and need {passes} passes over the input, but {} is read from stdin or a pipe and can only be read once. Raise --max-memory or read the input from a file",
+                stream.display()
+            );
+        }
+        if self.args.interleaved {
+            process_interleaved(
+                &self.reads_to_save,
+                &self.args.input,
+                &self.args.output,
+                self.args.output_type,
+                self.args.compression_level,
+                self.args.output_format,
+            )
+        } else if self.args.input.len() == 2 {
+            let mate_sync = if self.args.allow_orphans {
+                MateSync::AllowOrphans(self.args.orphan_output.as_deref())
+            } else {
+                MateSync::Strict
+            };
+            let (result, orphans) = process_paired_end(
                 &self.reads_to_save,
                 &self.args.input,
                 &self.args.output,
                 self.args.output_type,
                 self.args.compression_level,
                 self.args.output_format,
+                mate_sync,
             )?;
-
-            KractorResult {
-                reads_parsed: r1.reads_parsed + r2.reads_parsed,
-                reads_output: r1.reads_output + r2.reads_output,
-                input_format: r1.input_format,
-                output_format: r1.output_format,
+            if orphans.reads > 0 {
+                warn!(
+                    "{} reads in the paired input files had no mate, {} of them were written to the orphan output",
+                    orphans.reads, orphans.reads_output
+                );
             }
+            self.orphans = orphans;
+            Ok(result)
         } else {
             process_single_end(
                 &self.reads_to_save,
@@ -126,8 +396,47 @@ impl Kractor {
                 self.args.output_type,
                 self.args.compression_level,
                 self.args.output_format,
-            )?
+            )
+        }
+    }
+
+    // when the reads to drop are stored, reads missing from the Kraken2 output cannot be told apart from reads to keep
+    fn check_unknown_reads(&self, result: &KractorResult) -> Result<()> {
+        if !self.reads_to_save.is_inverted() {
+            return Ok(());
+        }
+        // Kraken2 writes one record per pair for paired input
+        let input_records = if self.args.interleaved || self.args.input.len() == 2 {
+            (result.reads_parsed - self.orphans.reads) / 2 + self.orphans.reads
+        } else {
+            result.reads_parsed
+        };
+        let kraken_records = self.reads_to_save.records();
+        if input_records != kraken_records {
+            bail!(
+                "The input has {input_records} records but the Kraken2 output has {kraken_records}. In --exclude mode, reads missing from the Kraken2 output are kept, so the output may hold reads that were never classified. Check that the Kraken2 output was produced from this input"
+            );
+        }
+        Ok(())
+    }
+
+    fn summarise(&mut self, result: KractorResult) {
+        let input_layout = if self.args.interleaved {
+            "interleaved"
+        } else if self.args.input.len() == 2 {
+            "paired"
+        } else {
+            "single"
         };
+        let reads_extracted_per_taxon = self.get_reads_extracted_per_taxon();
+        let taxon_names = reads_extracted_per_taxon
+            .keys()
+            .filter_map(|taxon_id| {
+                self.taxon_names
+                    .get(taxon_id)
+                    .map(|name| (*taxon_id, name.clone()))
+            })
+            .collect();
 
         self.summary = Some(Summary {
             kractor_version: env!("CARGO_PKG_VERSION").to_string(),
@@ -136,22 +445,109 @@ impl Kractor {
             output_sequence_format: result.output_format.to_string(),
             requested_taxon_ids: self.args.taxid.clone(),
             matched_taxon_ids: self.taxon_ids.clone(),
+            matched_taxon_origins: if self.args.parents
+                || self.args.children
+                || self.args.clade_rank.is_some()
+            {
+                self.taxon_origins.clone()
+            } else {
+                FxHashMap::default()
+            },
             requested_taxon_ids_not_found: self.missing_taxon_ids.clone(),
+            requested_taxon_names: self.args.taxon_name.clone(),
+            resolved_taxon_names: self
+                .resolved_names
+                .iter()
+                .map(|resolved| TaxonNameSummary {
+                    name: resolved.name.clone(),
+                    taxon_ids: resolved.taxon_ids.clone(),
+                })
+                .collect(),
             total_input_records: result.reads_parsed,
             total_output_records: result.reads_output,
             extraction_fraction: result.reads_output as f64 / result.reads_parsed as f64,
             assigned_reads_per_taxon: reads_extracted_per_taxon,
+            taxon_names,
+            confidence: self.args.confidence.map(|threshold| ConfidenceSummary {
+                threshold,
+                reassign: self.args.reassign,
+                reads_below_threshold: self.filter_stats.reads_below_confidence,
+                reads_reassigned: self.filter_stats.reads_reassigned,
+                reads_with_unplaced_kmers: self.filter_stats.reads_with_unplaced_kmers,
+                unplaced_kmers: self.filter_stats.unplaced_kmers,
+            }),
+            clade_fraction: self
+                .args
+                .min_clade_fraction
+                .map(|min_fraction| CladeFractionSummary {
+                    min_fraction,
+                    reads_below_threshold: self.filter_stats.reads_below_clade_fraction,
+                    selected_reads: self.filter_stats.clade_fractions.summary(),
+                }),
+            ambiguity: self
+                .args
+                .max_ambiguous_fraction
+                .map(|max_fraction| AmbiguitySummary {
+                    max_fraction,
+                    reads_above_threshold: self.filter_stats.reads_above_ambiguous_fraction,
+                    selected_reads: self.filter_stats.ambiguous_fractions.summary(),
+                }),
+            read_id_storage: self.args.max_memory.map(|max_memory| ReadIdStorageSummary {
+                max_memory,
+                spilled_to_disk: self.reads_to_save.is_spilled(),
+                input_passes: self.reads_to_save.passes(),
+            }),
+            kraken2: None,
+            exclusion: self.args.exclude.then_some(ExclusionSummary {
+                strict_min_kmers: self.args.strict_exclude_kmers,
+                strict_min_run: self.args.strict_exclude_run,
+                reads_removed_by_call: self.filter_stats.reads_excluded_by_call,
+                reads_removed_by_kmers: self.filter_stats.reads_excluded_by_kmers,
+            }),
+            orphans: self.args.allow_orphans.then(|| OrphanSummary {
+                orphaned_reads: self.orphans.reads,
+                orphaned_reads_output: self.orphans.reads_output,
+                orphan_output: self.args.orphan_output.clone(),
+            }),
+            parents: self.parents.take(),
         });
+    }
 
-        Ok(())
+    fn parents_summary(&self) -> ParentsSummary {
+        let nodes: FxHashMap<i32, &Tree> = self
+            .tree_nodes
+            .iter()
+            .map(|node| (node.taxon_id, node))
+            .collect();
+        let ancestors = self
+            .taxon_ids
+            .iter()
+            .filter(|taxon_id| self.taxon_origins.get(taxon_id) == Some(&TaxonOrigin::Ancestor))
+            .map(|taxon_id| {
+                let node = nodes.get(taxon_id);
+                AncestorSummary {
+                    taxon_id: *taxon_id,
+                    rank: node.and_then(|node| node.rank).map(|rank| rank.to_string()),
+                    name: node.map(|node| node.name.clone()).unwrap_or_default(),
+                }
+            })
+            .collect();
+        ParentsSummary {
+            up_to: self.args.parents_up_to.map(|limit| limit.to_string()),
+            ancestors,
+        }
     }
 
     fn output_summary(&self) -> Result<()> {
-        if self.args.summary
-            && let Some(summary) = &self.summary
-        {
+        if let Some(summary) = &self.summary {
             let json = serde_json::to_string_pretty(summary)?;
-            println!("{json}");
+            if let Some(summary_file) = &self.args.summary_file {
+                fs::write(summary_file, format!("{json}\n")).wrap_err_with(|| {
+                    format!("Failed to write summary file: {}", summary_file.display())
+                })?;
+            } else if self.args.summary {
+                println!("{json}");
+            }
         }
         Ok(())
     }
@@ -173,44 +569,166 @@ struct Summary {
     output_sequence_format: String,
     requested_taxon_ids: Vec<i32>,
     matched_taxon_ids: Vec<i32>,
+    #[serde(default, skip_serializing_if = "FxHashMap::is_empty")]
+    matched_taxon_origins: FxHashMap<i32, TaxonOrigin>,
     requested_taxon_ids_not_found: Vec<i32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    requested_taxon_names: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    resolved_taxon_names: Vec<TaxonNameSummary>,
     total_input_records: usize,
     total_output_records: usize,
     extraction_fraction: f64,
     assigned_reads_per_taxon: FxHashMap<i32, usize>,
+    #[serde(default, skip_serializing_if = "FxHashMap::is_empty")]
+    taxon_names: FxHashMap<i32, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    confidence: Option<ConfidenceSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clade_fraction: Option<CladeFractionSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ambiguity: Option<AmbiguitySummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read_id_storage: Option<ReadIdStorageSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kraken2: Option<Kraken2Summary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exclusion: Option<ExclusionSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    orphans: Option<OrphanSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parents: Option<ParentsSummary>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TaxonNameSummary {
+    name: String,
+    taxon_ids: Vec<i32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ConfidenceSummary {
+    threshold: f64,
+    reassign: bool,
+    reads_below_threshold: usize,
+    reads_reassigned: usize,
+    reads_with_unplaced_kmers: usize,
+    unplaced_kmers: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CladeFractionSummary {
+    min_fraction: f64,
+    reads_below_threshold: usize,
+    selected_reads: FractionSummary,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AmbiguitySummary {
+    max_fraction: f64,
+    reads_above_threshold: usize,
+    selected_reads: FractionSummary,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ReadIdStorageSummary {
+    max_memory: usize,
+    spilled_to_disk: bool,
+    input_passes: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OrphanSummary {
+    orphaned_reads: usize,
+    orphaned_reads_output: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    orphan_output: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ParentsSummary {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    up_to: Option<String>,
+    ancestors: Vec<AncestorSummary>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AncestorSummary {
+    taxon_id: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rank: Option<String>,
+    name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Kraken2Summary {
+    command: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    report: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExclusionSummary {
+    strict_min_kmers: Option<u32>,
+    strict_min_run: Option<u32>,
+    reads_removed_by_call: usize,
+    reads_removed_by_kmers: usize,
 }
 
 #[cfg(test)]
 mod tests {
-    use std::path::PathBuf;
+    use std::{num::NonZeroUsize, path::PathBuf};
 
     use tempfile::tempdir;
 
     use super::*;
-    use crate::cli::OutputFormat;
+    use crate::{Cli, cli::OutputFormat};
 
     #[test]
     fn test_output_doesnt_exist() {
         let temp_dir = tempdir().unwrap();
         let output_file = temp_dir.path().join("output.fastq");
         let input_files = vec![PathBuf::from("input1.fastq"), PathBuf::from("input2.fastq")];
-        let args = Cli {
+        let args = ExtractArgs {
             input: input_files,
             output: vec![output_file],
-            kraken: PathBuf::from("kraken_output.txt"),
-            report: None,
             taxid: vec![1, 2, 3],
+            taxon_name: Vec::new(),
+            ignore_case: false,
+            name_regex: false,
             output_type: None,
             compression_level: niffler::Level::One,
             parents: false,
             children: false,
             exclude: false,
+            confidence: None,
+            reassign: false,
+            min_clade_fraction: None,
+            strict_exclude_kmers: None,
+            strict_exclude_run: None,
+            max_ambiguous_fraction: None,
+            per_mate: false,
+            interleaved: false,
+            allow_orphans: false,
+            orphan_output: None,
+            parents_up_to: None,
+            clade_rank: None,
+            min_rank: None,
+            max_rank: None,
+            threads: NonZeroUsize::MIN,
+            max_memory: None,
+            ordered: false,
             output_format: OutputFormat::Auto,
             summary: false,
+            summary_file: None,
             no_report_header_detect: false,
             verbose: false,
         };
-        let kractor = Kractor::new(args);
+        let kractor = Kractor::new(
+            args,
+            KrakenSource::Path(PathBuf::from("kraken_output.txt")),
+            None,
+        );
         assert!(kractor.validate_outputs().is_ok());
     }
 
@@ -220,46 +738,92 @@ mod tests {
         let output_file = temp_dir.path().join("output.fastq");
         std::fs::File::create(&output_file).unwrap();
         let input_files = vec![PathBuf::from("input.fastq")];
-        let args = Cli {
+        let args = ExtractArgs {
             input: input_files,
             output: vec![output_file],
-            kraken: PathBuf::from("kraken_output.txt"),
-            report: None,
             taxid: vec![1, 2, 3],
+            taxon_name: Vec::new(),
+            ignore_case: false,
+            name_regex: false,
             output_type: None,
             compression_level: niffler::Level::One,
             parents: false,
             children: false,
             exclude: false,
+            confidence: None,
+            reassign: false,
+            min_clade_fraction: None,
+            strict_exclude_kmers: None,
+            strict_exclude_run: None,
+            max_ambiguous_fraction: None,
+            per_mate: false,
+            interleaved: false,
+            allow_orphans: false,
+            orphan_output: None,
+            parents_up_to: None,
+            clade_rank: None,
+            min_rank: None,
+            max_rank: None,
+            threads: NonZeroUsize::MIN,
+            max_memory: None,
+            ordered: false,
             output_format: OutputFormat::Auto,
             summary: false,
+            summary_file: None,
             no_report_header_detect: false,
             verbose: false,
         };
-        let kractor = Kractor::new(args);
+        let kractor = Kractor::new(
+            args,
+            KrakenSource::Path(PathBuf::from("kraken_output.txt")),
+            None,
+        );
         assert!(kractor.validate_outputs().is_err());
     }
 
     #[test]
     fn test_get_reads_extracted_per_taxon() {
         let input_files = vec![PathBuf::from("input.fastq")];
-        let args = Cli {
+        let args = ExtractArgs {
             input: input_files,
             output: vec![PathBuf::from("output.fastq")],
-            kraken: PathBuf::from("kraken_output.txt"),
-            report: None,
             taxid: vec![2901879, 227984],
+            taxon_name: Vec::new(),
+            ignore_case: false,
+            name_regex: false,
             output_type: None,
             compression_level: niffler::Level::One,
             parents: false,
             children: false,
             exclude: false,
+            confidence: None,
+            reassign: false,
+            min_clade_fraction: None,
+            strict_exclude_kmers: None,
+            strict_exclude_run: None,
+            max_ambiguous_fraction: None,
+            per_mate: false,
+            interleaved: false,
+            allow_orphans: false,
+            orphan_output: None,
+            parents_up_to: None,
+            clade_rank: None,
+            min_rank: None,
+            max_rank: None,
+            threads: NonZeroUsize::MIN,
+            max_memory: None,
+            ordered: false,
             output_format: OutputFormat::Auto,
             summary: false,
+            summary_file: None,
             no_report_header_detect: false,
             verbose: false,
         };
-        let mut kractor = Kractor::new(args);
+        let mut kractor = Kractor::new(
+            args,
+            KrakenSource::Path(PathBuf::from("kraken_output.txt")),
+            None,
+        );
         kractor.taxon_ids = vec![2901879, 227984];
         kractor.reads_per_taxon.insert(227984, 257);
 
@@ -268,4 +832,286 @@ mod tests {
         assert_eq!(reads_extracted_per_taxon.get(&2901879), Some(&0));
         assert_eq!(reads_extracted_per_taxon.get(&227984), Some(&257));
     }
+
+    fn extract_args(dir: &std::path::Path, kraken_output: &str, extra_args: &[&str]) -> Cli {
+        use clap::Parser;
+
+        let input = dir.join("input.fastq");
+        std::fs::write(
+            &input,
+            "@read1\nAAAA\n+\n!!!!\n@read2\nCCCC\n+\n!!!!\n@read3\nGGGG\n+\n!!!!\n@read4\nTTTT\n+\n!!!!\n",
+        )
+        .unwrap();
+        let kraken = dir.join("kraken_output.txt");
+        std::fs::write(&kraken, kraken_output).unwrap();
+
+        let mut args = vec![
+            "kractor".to_string(),
+            "-k".to_string(),
+            kraken.display().to_string(),
+            "-i".to_string(),
+            input.display().to_string(),
+            "-o".to_string(),
+            dir.join("output.fastq").display().to_string(),
+        ];
+        args.extend(extra_args.iter().map(|arg| arg.to_string()));
+        Cli::try_parse_from(args).unwrap()
+    }
+
+    #[test]
+    fn test_exclude_read_missing_from_kraken_output() {
+        let dir = tempdir().unwrap();
+        // fewer reads are dropped than kept, so the reads to drop are stored and read4 would be kept unseen
+        let cli = extract_args(
+            dir.path(),
+            "C\tread1\t562\t4\t562:1\nU\tread2\t0\t4\t0:1\nU\tread3\t0\t4\t0:1\n",
+            &["-t", "562", "--exclude"],
+        );
+
+        let err = run(cli.extract, cli.kraken.unwrap(), cli.report).unwrap_err();
+
+        assert!(
+            err.to_string()
+                .starts_with("The input has 4 records but the Kraken2 output has 3")
+        );
+
+        let cli = extract_args(
+            dir.path(),
+            "C\tread1\t562\t4\t562:1\nU\tread2\t0\t4\t0:1\nU\tread3\t0\t4\t0:1\nU\tread4\t0\t4\t0:1\n",
+            &["-t", "562", "--exclude"],
+        );
+        std::fs::remove_file(dir.path().join("output.fastq")).unwrap();
+
+        run(cli.extract, cli.kraken.unwrap(), cli.report).unwrap();
+
+        let output = std::fs::read_to_string(dir.path().join("output.fastq")).unwrap();
+        assert_eq!(
+            output,
+            "@read2\nCCCC\n+\n!!!!\n@read3\nGGGG\n+\n!!!!\n@read4\nTTTT\n+\n!!!!\n"
+        );
+    }
+
+    #[test]
+    fn test_parents_summary_with_confidence() {
+        let dir = tempdir().unwrap();
+        let report = dir.path().join("report.txt");
+        std::fs::write(
+            &report,
+            "100.00\t4\t0\tR\t1\troot\n100.00\t4\t0\tF\t543\t  Enterobacteriaceae\n100.00\t4\t4\tS\t562\t    Escherichia coli\n",
+        )
+        .unwrap();
+        let summary_file = dir.path().join("summary.json");
+        let cli = extract_args(
+            dir.path(),
+            "C\tread1\t562\t4\t562:1\nC\tread2\t562\t4\t562:1\nC\tread3\t562\t4\t562:1\nC\tread4\t562\t4\t562:1\n",
+            &[
+                "-t",
+                "562",
+                "-r",
+                &report.display().to_string(),
+                "--parents",
+                "--confidence",
+                "0.5",
+                "--summary-file",
+                &summary_file.display().to_string(),
+            ],
+        );
+
+        run(cli.extract, cli.kraken.unwrap(), cli.report).unwrap();
+
+        let summary: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(summary_file).unwrap()).unwrap();
+        let mut ancestors = summary["parents"]["ancestors"].as_array().unwrap().clone();
+        ancestors.sort_by_key(|ancestor| ancestor["taxon_id"].as_i64());
+        assert_eq!(
+            ancestors,
+            [
+                serde_json::json!({"taxon_id": 1, "rank": "R", "name": "root"}),
+                serde_json::json!({"taxon_id": 543, "rank": "F", "name": "Enterobacteriaceae"}),
+            ]
+        );
+    }
+
+    // stands in for Kraken2, writing a fixed classification of three reads and its report
+    #[cfg(unix)]
+    const KRAKEN2_STUB: &str = r#"#!/bin/sh
+while [ $# -gt 0 ]; do
+    case "$1" in
+        --output) output="$2"; shift ;;
+        --report) report="$2"; shift ;;
+        --fail) echo "database not found" >&2; exit 3 ;;
+    esac
+    shift
+done
+lines='C\tread1\t562\t4\t562:1\nU\tread2\t0\t4\t0:1\nC\tread3\t562\t4\t562:1\n'
+if [ "$output" = "-" ]; then printf "$lines"; else printf "$lines" > "$output"; fi
+printf ' 33.33\t1\t1\tU\t0\tunclassified\n 66.67\t2\t0\tR\t1\troot\n 66.67\t2\t2\tS\t562\t  Escherichia coli\n' > "$report"
+"#;
+
+    #[cfg(unix)]
+    fn classify_args(dir: &std::path::Path, extra_args: &[&str]) -> ClassifyArgs {
+        use std::os::unix::fs::PermissionsExt;
+
+        use clap::Parser;
+
+        use crate::{Cli, cli::Command};
+
+        let stub = dir.join("kraken2");
+        std::fs::write(&stub, KRAKEN2_STUB).unwrap();
+        std::fs::set_permissions(&stub, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let input = dir.join("input.fastq");
+        std::fs::write(
+            &input,
+            "@read1\nAAAA\n+\n!!!!\n@read2\nCCCC\n+\n!!!!\n@read3\nGGGG\n+\n!!!!\n",
+        )
+        .unwrap();
+
+        let mut args = vec![
+            "kractor".to_string(),
+            "classify-and-extract".to_string(),
+            "--db".to_string(),
+            "db".to_string(),
+            "--kraken2".to_string(),
+            stub.display().to_string(),
+            "-i".to_string(),
+            input.display().to_string(),
+            "-o".to_string(),
+            dir.join("output.fastq").display().to_string(),
+        ];
+        args.extend(extra_args.iter().map(|arg| arg.to_string()));
+        match Cli::try_parse_from(args).unwrap().command {
+            Some(Command::ClassifyAndExtract(classify_args)) => classify_args,
+            None => panic!("classify-and-extract was not parsed"),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_classify_and_extract_streams_output() {
+        let dir = tempdir().unwrap();
+        let args = classify_args(dir.path(), &["-t", "562"]);
+
+        classify_and_extract(args).unwrap();
+
+        let output = std::fs::read_to_string(dir.path().join("output.fastq")).unwrap();
+        assert_eq!(output, "@read1\nAAAA\n+\n!!!!\n@read3\nGGGG\n+\n!!!!\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_classify_and_extract_paired_mate_suffixes() {
+        let dir = tempdir().unwrap();
+        let mut args = classify_args(dir.path(), &["-t", "562"]);
+        // the stub writes read1 to read3, as Kraken2 --paired does for mates named read1/1 and read1/2
+        let inputs = [
+            dir.path().join("input_R1.fastq"),
+            dir.path().join("input_R2.fastq"),
+        ];
+        for (input, mate) in inputs.iter().zip(["1", "2"]) {
+            let records: String = ["AAAA", "CCCC", "GGGG"]
+                .iter()
+                .enumerate()
+                .map(|(index, seq)| format!("@read{}/{mate}\n{seq}\n+\n!!!!\n", index + 1))
+                .collect();
+            std::fs::write(input, records).unwrap();
+        }
+        let outputs = [
+            dir.path().join("output_R1.fastq"),
+            dir.path().join("output_R2.fastq"),
+        ];
+        args.extract.input = inputs.to_vec();
+        args.extract.output = outputs.to_vec();
+
+        classify_and_extract(args).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&outputs[0]).unwrap(),
+            "@read1/1\nAAAA\n+\n!!!!\n@read3/1\nGGGG\n+\n!!!!\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&outputs[1]).unwrap(),
+            "@read1/2\nAAAA\n+\n!!!!\n@read3/2\nGGGG\n+\n!!!!\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_classify_and_extract_with_report() {
+        let dir = tempdir().unwrap();
+        let report = dir.path().join("report.txt");
+        let args = classify_args(
+            dir.path(),
+            &[
+                "-t",
+                "562",
+                "--children",
+                "--kraken2-report",
+                &report.display().to_string(),
+            ],
+        );
+
+        classify_and_extract(args).unwrap();
+
+        let output = std::fs::read_to_string(dir.path().join("output.fastq")).unwrap();
+        assert_eq!(output, "@read1\nAAAA\n+\n!!!!\n@read3\nGGGG\n+\n!!!!\n");
+        assert!(report.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_classify_and_extract_summary_file() {
+        let dir = tempdir().unwrap();
+        let summary_file = dir.path().join("summary.json");
+        let args = classify_args(
+            dir.path(),
+            &[
+                "-t",
+                "562",
+                "--summary-file",
+                &summary_file.display().to_string(),
+            ],
+        );
+
+        classify_and_extract(args).unwrap();
+
+        let summary: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(summary_file).unwrap()).unwrap();
+        assert_eq!(summary["total_output_records"], 2);
+        assert!(summary["kraken2"]["command"].is_array());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_classify_and_extract_kraken2_args() {
+        let dir = tempdir().unwrap();
+        let args = classify_args(
+            dir.path(),
+            &[
+                "-t",
+                "562",
+                "--",
+                "--confidence",
+                "0.1",
+                "--unclassified-out",
+                "a b.fq",
+            ],
+        );
+
+        assert_eq!(
+            args.kraken2_args,
+            ["--confidence", "0.1", "--unclassified-out", "a b.fq"]
+        );
+        assert_eq!(args.extract.taxid, [562]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_classify_and_extract_kraken2_failure() {
+        let dir = tempdir().unwrap();
+        let args = classify_args(dir.path(), &["-t", "562", "--", "--fail"]);
+
+        let err = classify_and_extract(args).unwrap_err();
+
+        assert!(err.to_string().starts_with("Kraken2 failed"));
+    }
 }
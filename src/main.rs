@@ -1,33 +1,121 @@
+pub mod classify;
 pub mod cli;
 pub mod extract;
+pub mod filter;
 pub mod kractor;
 pub mod parsers;
+pub mod read_ids;
 
-use std::io::Write;
+use std::{io::Write, path::Path};
 
 use chrono::Local;
 use clap::Parser;
-use color_eyre::{Result, eyre::bail};
+use color_eyre::{
+    Result,
+    eyre::{bail, eyre},
+};
 use env_logger::{Builder, fmt::Color};
 use log::LevelFilter;
 
 pub use crate::cli::Cli;
+use crate::{
+    cli::{Command, ExtractArgs},
+    extract::NameMatching,
+};
 
 fn main() -> Result<()> {
     color_eyre::install()?;
 
     let args = Cli::parse();
-    init_logging(args.verbose);
 
-    if args.input.len() != args.output.len() {
-        bail!("Number of input and output files must match");
+    if let Some(Command::ClassifyAndExtract(classify_args)) = args.command {
+        init_logging(classify_args.extract.verbose);
+        check_file_counts(&classify_args.extract)?;
+        check_taxon_names(&classify_args.extract)?;
+        if classify_args.extract.allow_orphans {
+            bail!(
+                "Orphaned reads cannot be allowed with classify-and-extract, as the Kraken2 output is read in order"
+            );
+        }
+        if classify_args
+            .extract
+            .input
+            .iter()
+            .any(|path| is_stdin(path))
+        {
+            bail!(
+                "The input cannot be read from stdin with classify-and-extract, as Kraken2 also reads it"
+            );
+        }
+        return kractor::classify_and_extract(classify_args);
     }
 
-    kractor::run(args)?;
+    init_logging(args.extract.verbose);
+    check_file_counts(&args.extract)?;
+    check_taxon_names(&args.extract)?;
+
+    // clap requires the Kraken2 output when no subcommand is given
+    let kraken = args
+        .kraken
+        .ok_or_else(|| eyre!("A Kraken2 output file is required"))?;
+    let stdin_count = args
+        .extract
+        .input
+        .iter()
+        .chain([&kraken])
+        .chain(&args.report)
+        .filter(|path| is_stdin(path))
+        .count();
+    if stdin_count > 1 {
+        bail!("Only one of the input, Kraken2 output and report can be read from stdin");
+    }
+
+    kractor::run(args.extract, kraken, args.report)?;
 
     Ok(())
 }
 
+fn check_file_counts(args: &ExtractArgs) -> Result<()> {
+    if args.interleaved {
+        if args.input.len() != 1 {
+            bail!("Interleaved input must be a single file");
+        }
+    } else if args.input.len() < args.output.len() {
+        // paired input can also be written interleaved to a single output
+        bail!("Number of input and output files must match");
+    }
+    if args.allow_orphans && args.input.len() != 2 {
+        bail!("Orphaned reads can only be allowed for two paired input files");
+    }
+    let stdout_count = args
+        .output
+        .iter()
+        .chain(&args.orphan_output)
+        .filter(|path| parsers::fastx::is_stdout(path))
+        .count();
+    if stdout_count > 1 {
+        bail!("Only one output can be written to stdout");
+    }
+    if stdout_count == 1 && args.summary && args.summary_file.is_none() {
+        bail!(
+            "The extracted reads and the JSON summary cannot both be written to stdout, use --summary-file to write the summary to a file"
+        );
+    }
+    Ok(())
+}
+
+fn check_taxon_names(args: &ExtractArgs) -> Result<()> {
+    NameMatching {
+        ignore_case: args.ignore_case,
+        regex: args.name_regex,
+    }
+    .validate(&args.taxon_name)
+}
+
+fn is_stdin(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
 fn init_logging(verbose: bool) {
     let level_filter = if verbose {
         LevelFilter::Debug
@@ -1,15 +1,20 @@
 use std::{
-    fmt, fs, io,
-    path::Path,
+    fmt, fs,
+    io::{self, BufRead, Read, Write},
+    path::{Path, PathBuf},
     time::{Duration, Instant},
 };
 
-use color_eyre::eyre::{Context, Result, eyre};
+use color_eyre::eyre::{Context, Result, bail};
 use crossbeam::channel::{Receiver, Sender};
-use fxhash::FxHashSet;
+use fxhash::FxHashMap;
 use log::{debug, trace};
 
-use crate::cli::OutputFormat;
+use crate::{
+    cli::OutputFormat,
+    parsers::{decompressed_reader, kraken::OrderedKrakenOutput},
+    read_ids::ReadSelection,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FastxFormat {
@@ -17,15 +22,6 @@ pub enum FastxFormat {
     Fastq,
 }
 
-impl From<needletail::parser::Format> for FastxFormat {
-    fn from(format: needletail::parser::Format) -> Self {
-        match format {
-            needletail::parser::Format::Fasta => Self::Fasta,
-            needletail::parser::Format::Fastq => Self::Fastq,
-        }
-    }
-}
-
 impl fmt::Display for FastxFormat {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -42,26 +38,124 @@ pub struct FastxRecord {
     pub qual: Option<Vec<u8>>,
 }
 
+impl From<&needletail::parser::SequenceRecord<'_>> for FastxRecord {
+    fn from(record: &needletail::parser::SequenceRecord<'_>) -> Self {
+        Self {
+            id: record.id().to_vec(),
+            seq: record.seq().into_owned(),
+            qual: record.qual().map(Vec::from),
+        }
+    }
+}
+
+/// How paired input files that fall out of step are handled. `T` is where the reads left without a mate go.
+#[derive(Debug, Clone, Copy)]
+pub enum MateSync<T> {
+    /// Fail on the first mates with different read IDs, or when one file ends before the other.
+    Strict,
+    /// Pair mates up by read ID, counting the reads left without a mate as orphans and sending those selected to
+    /// the orphan output, if there is one.
+    AllowOrphans(Option<T>),
+}
+
+/// The pairs and orphaned reads found in paired input files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PairedCounts {
+    pub pairs: usize,
+    pub orphaned_reads: usize,
+}
+
+/// A FASTA or FASTQ input, opened once with its format detected from the first byte, so that pipes, process
+/// substitution and stdin (`-`) can be read.
+pub struct FastxInput {
+    path: PathBuf,
+    format: FastxFormat,
+    // the stream the format was detected from, parsed by the first pass over the input
+    stream: Option<Box<dyn BufRead + Send>>,
+}
+
+impl FastxInput {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut stream = open_fastx_stream(path)
+            .wrap_err_with(|| format!("Failed to open input file: {}", path.display()))?;
+        // peeking leaves the first record in the buffer for the parser
+        let format = match stream
+            .fill_buf()
+            .wrap_err_with(|| format!("Failed to read input file: {}", path.display()))?
+            .first()
+        {
+            Some(b'>') => FastxFormat::Fasta,
+            Some(b'@') => FastxFormat::Fastq,
+            Some(_) => bail!(
+                "Input file is not in FASTA or FASTQ format: {}",
+                path.display()
+            ),
+            None => bail!(
+                "No FASTA or FASTQ records found in input file: {}",
+                path.display()
+            ),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            format,
+            stream: Some(stream),
+        })
+    }
+
+    pub fn format(&self) -> FastxFormat {
+        self.format
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // the first pass parses the stream opened to detect the format, later passes open the file again
+    fn reader(&mut self) -> Result<Box<dyn needletail::FastxReader>> {
+        let stream = match self.stream.take() {
+            Some(stream) => stream,
+            None if is_fastx_stream(&self.path) => bail!(
+                "Input read from stdin or a pipe cannot be read more than once: {}",
+                self.path.display()
+            ),
+            None => open_fastx_stream(&self.path)
+                .wrap_err_with(|| format!("Failed to open input file: {}", self.path.display()))?,
+        };
+        needletail::parse_fastx_reader(stream)
+            .wrap_err_with(|| format!("Failed to parse FASTX file: {}", self.path.display()))
+    }
+}
+
+/// Whether the input is read from stdin or a pipe, and so can only be read once.
+pub fn is_fastx_stream(path: &Path) -> bool {
+    path.as_os_str() == "-" || fs::metadata(path).is_ok_and(|metadata| !metadata.is_file())
+}
+
+fn open_fastx_stream(path: &Path) -> Result<Box<dyn BufRead + Send>> {
+    let stream: Box<dyn Read + Send> = if path.as_os_str() == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(fs::File::open(path)?)
+    };
+    decompressed_reader(stream)
+}
+
 pub fn parse_fastx(
-    file_path: &Path,
-    reads_to_save: &FxHashSet<Vec<u8>>,
+    input: &mut FastxInput,
+    reads_to_save: &ReadSelection,
     tx: &Sender<FastxRecord>,
-) -> Result<(usize, FastxFormat)> {
+) -> Result<usize> {
     const PROGRESS_UPDATE_INTERVAL: Duration = Duration::from_millis(1500);
 
     let mut num_reads = 0;
-    let mut input_format = None;
     let mut last_progress_update = Instant::now();
 
-    let mut fastx_reader = needletail::parse_fastx_file(file_path)
-        .wrap_err_with(|| format!("Failed to parse FASTX file: {}", file_path.display()))?;
+    let mut fastx_reader = input.reader()?;
 
     while let Some(result) = fastx_reader.next() {
         let record = result
             .wrap_err_with(|| format!("Error reading FASTX record at position {num_reads}"))?;
 
-        input_format.get_or_insert(record.format().into());
-
         let record_id = record.id();
         let read_id = read_id(record_id);
         if reads_to_save.contains(read_id) {
@@ -81,36 +175,347 @@ pub fn parse_fastx(
         }
     }
 
-    let input_format = input_format.ok_or_else(|| {
-        eyre!(
-            "No FASTA or FASTQ records found in input file: {}",
-            file_path.display()
-        )
+    Ok(num_reads)
+}
+
+/// Reads the input alongside Kraken2 output written in the same order, sending each read selected on its matching
+/// line. Fails as soon as a read ID differs from the one on its line, or either file ends first.
+pub fn parse_fastx_ordered(
+    input: &mut FastxInput,
+    kraken: &mut OrderedKrakenOutput,
+    tx: &Sender<FastxRecord>,
+) -> Result<usize> {
+    let mut num_reads = 0;
+
+    let mut fastx_reader = input.reader()?;
+
+    while let Some(result) = fastx_reader.next() {
+        let record = result
+            .wrap_err_with(|| format!("Error reading FASTX record at position {num_reads}"))?;
+        num_reads += 1;
+
+        if next_ordered_read(kraken, &[(input.path(), record.id())], num_reads, false)? {
+            tx.send(FastxRecord {
+                id: record.id().to_vec(),
+                seq: record.seq().into_owned(),
+                qual: record.qual().map(Vec::from),
+            })
+            .wrap_err("Error sending record")?;
+        }
+    }
+    ensure_kraken_output_ended(kraken, input.path())?;
+
+    Ok(num_reads)
+}
+
+/// Reads paired-end input files in step, checking each pair of mates shares a read ID. Pairs are kept or dropped
+/// together on the ID of the first mate, and sent to one writer per mate or both to a single interleaved writer.
+pub fn parse_paired_fastx(
+    inputs: &mut [FastxInput; 2],
+    reads_to_save: &ReadSelection,
+    txs: &[Sender<FastxRecord>],
+    mate_sync: MateSync<&Sender<FastxRecord>>,
+) -> Result<PairedCounts> {
+    match mate_sync {
+        MateSync::Strict => {
+            let pairs = for_each_paired_record(inputs, txs, |mates, _| {
+                Ok(reads_to_save.contains(mate_id(mates[0].1)))
+            })?;
+            Ok(PairedCounts {
+                pairs,
+                orphaned_reads: 0,
+            })
+        }
+        MateSync::AllowOrphans(orphan_tx) => {
+            pair_mates_by_id(inputs, reads_to_save, txs, orphan_tx)
+        }
+    }
+}
+
+/// Paired-end version of [`parse_fastx_ordered`]. Both mates are read in step and must share the ID on their
+/// Kraken2 output line, returning the number of pairs read.
+pub fn parse_paired_fastx_ordered(
+    inputs: &mut [FastxInput; 2],
+    kraken: &mut OrderedKrakenOutput,
+    txs: &[Sender<FastxRecord>],
+) -> Result<usize> {
+    let num_pairs = for_each_paired_record(inputs, txs, |mates, position| {
+        next_ordered_read(kraken, mates, position, true)
     })?;
+    ensure_kraken_output_ended(kraken, inputs[0].path())?;
 
-    Ok((num_reads, input_format))
+    Ok(num_pairs)
 }
 
-pub fn detect_fastx_format(file_path: &Path) -> Result<FastxFormat> {
-    let mut fastx_reader = needletail::parse_fastx_file(file_path)
-        .wrap_err_with(|| format!("Failed to parse FASTX file: {}", file_path.display()))?;
+// reads both input files in step, failing if one ends first or the mates have different read IDs. `select` decides from both mates whether the pair is
+// kept, and kept pairs go to one writer per mate, or both to a single interleaved writer
+fn for_each_paired_record(
+    inputs: &mut [FastxInput; 2],
+    txs: &[Sender<FastxRecord>],
+    mut select: impl FnMut(&[(&Path, &[u8])], usize) -> Result<bool>,
+) -> Result<usize> {
+    let mut num_pairs = 0;
+
+    let mut reader1 = inputs[0].reader()?;
+    let mut reader2 = inputs[1].reader()?;
+    let file_paths = [inputs[0].path(), inputs[1].path()];
+
+    loop {
+        let (record1, record2) = match (reader1.next(), reader2.next()) {
+            (None, None) => break,
+            (Some(record1), Some(record2)) => (record1, record2),
+            (record1, _) => bail!(
+                "Paired input files have a different number of reads: {} ended after {num_pairs} reads",
+                file_paths[usize::from(record1.is_some())].display()
+            ),
+        };
+        let record1 = record1
+            .wrap_err_with(|| format!("Error reading FASTX record at position {num_pairs}"))?;
+        let record2 = record2
+            .wrap_err_with(|| format!("Error reading FASTX record at position {num_pairs}"))?;
+        num_pairs += 1;
+
+        if mate_id(record1.id()) != mate_id(record2.id()) {
+            bail!(
+                "Paired input files are out of sync at pair {num_pairs}: {} has read {} but {} has read {}",
+                file_paths[0].display(),
+                String::from_utf8_lossy(read_id(record1.id())),
+                file_paths[1].display(),
+                String::from_utf8_lossy(read_id(record2.id()))
+            );
+        }
+        let mates = [(file_paths[0], record1.id()), (file_paths[1], record2.id())];
+        if select(&mates, num_pairs)? {
+            send_pair(
+                txs,
+                [FastxRecord::from(&record1), FastxRecord::from(&record2)],
+            )?;
+        }
+    }
 
-    let record = fastx_reader
-        .next()
-        .ok_or_else(|| {
-            eyre!(
-                "No FASTA or FASTQ records found in input file: {}",
-                file_path.display()
-            )
-        })?
-        .wrap_err_with(|| {
+    Ok(num_pairs)
+}
+
+// reads both input files in step like `for_each_paired_record`, but pairs mates up by read ID when the files fall out
+// of step. Reads are held until their mate turns up, and those still without one when both files end are orphans
+fn pair_mates_by_id(
+    inputs: &mut [FastxInput; 2],
+    reads_to_save: &ReadSelection,
+    txs: &[Sender<FastxRecord>],
+    orphan_tx: Option<&Sender<FastxRecord>>,
+) -> Result<PairedCounts> {
+    let mut counts = PairedCounts::default();
+    let mut orphans = Vec::new();
+    // reads waiting for their mate from the other file, by read ID, with the position they were read at
+    let mut unpaired: [FxHashMap<Vec<u8>, (usize, FastxRecord)>; 2] = Default::default();
+    let mut position = 0;
+
+    let mut reader1 = inputs[0].reader()?;
+    let mut reader2 = inputs[1].reader()?;
+
+    loop {
+        let (record1, record2) = match (reader1.next(), reader2.next()) {
+            (None, None) => break,
+            (record1, record2) => (
+                record1.transpose().wrap_err_with(|| {
+                    format!("Error reading FASTX record at position {position}")
+                })?,
+                record2.transpose().wrap_err_with(|| {
+                    format!("Error reading FASTX record at position {position}")
+                })?,
+            ),
+        };
+        position += 1;
+
+        if let (Some(record1), Some(record2)) = (&record1, &record2)
+            && mate_id(record1.id()) == mate_id(record2.id())
+        {
+            counts.pairs += 1;
+            if reads_to_save.contains(mate_id(record1.id())) {
+                send_pair(
+                    txs,
+                    [FastxRecord::from(record1), FastxRecord::from(record2)],
+                )?;
+            }
+            continue;
+        }
+
+        let records = [record1.as_ref(), record2.as_ref()];
+        for (mate, record) in records.into_iter().enumerate() {
+            let Some(record) = record else {
+                continue;
+            };
+            let record = FastxRecord::from(record);
+            let id = mate_id(&record.id).to_vec();
+            match unpaired[1 - mate].remove(&id) {
+                Some((_, other)) => {
+                    counts.pairs += 1;
+                    let mates = if mate == 0 {
+                        [record, other]
+                    } else {
+                        [other, record]
+                    };
+                    if reads_to_save.contains(mate_id(&mates[0].id)) {
+                        send_pair(txs, mates)?;
+                    }
+                }
+                None => {
+                    // a repeated read ID leaves the earlier read without a mate
+                    if let Some(earlier) = unpaired[mate].insert(id, (position, record)) {
+                        orphans.push(earlier);
+                    }
+                }
+            }
+        }
+    }
+
+    orphans.extend(unpaired.into_iter().flat_map(|reads| reads.into_values()));
+    orphans.sort_by_key(|(position, _)| *position);
+    counts.orphaned_reads = orphans.len();
+    if let Some(orphan_tx) = orphan_tx {
+        for (_, record) in orphans {
+            if reads_to_save.contains(mate_id(&record.id)) {
+                orphan_tx.send(record).wrap_err("Error sending record")?;
+            }
+        }
+    }
+
+    Ok(counts)
+}
+
+/// Reads interleaved paired-end input, where each read is followed by its mate. Pairs are kept or dropped together on
+/// the ID of the first mate, returning the number of pairs read.
+pub fn parse_interleaved_fastx(
+    input: &mut FastxInput,
+    reads_to_save: &ReadSelection,
+    txs: &[Sender<FastxRecord>],
+) -> Result<usize> {
+    for_each_interleaved_pair(input, txs, |record_id, _| {
+        Ok(reads_to_save.contains(mate_id(record_id)))
+    })
+}
+
+/// Interleaved version of [`parse_fastx_ordered`], where each pair of mates shares a line of the Kraken2 output.
+pub fn parse_interleaved_fastx_ordered(
+    input: &mut FastxInput,
+    kraken: &mut OrderedKrakenOutput,
+    txs: &[Sender<FastxRecord>],
+) -> Result<usize> {
+    let file_path = input.path().to_path_buf();
+    let num_pairs = for_each_interleaved_pair(input, txs, |record_id, position| {
+        next_ordered_read(kraken, &[(&file_path, record_id)], position, true)
+    })?;
+    ensure_kraken_output_ended(kraken, &file_path)?;
+
+    Ok(num_pairs)
+}
+
+// reads the input two records at a time, checking the mates share a read ID. `select` decides from the first mate
+// whether the pair is kept, and kept pairs go to one writer per mate, or both to a single interleaved writer
+fn for_each_interleaved_pair(
+    input: &mut FastxInput,
+    txs: &[Sender<FastxRecord>],
+    mut select: impl FnMut(&[u8], usize) -> Result<bool>,
+) -> Result<usize> {
+    let mut num_pairs = 0;
+
+    let mut fastx_reader = input.reader()?;
+
+    while let Some(result) = fastx_reader.next() {
+        let record1 = result.wrap_err_with(|| {
+            format!("Error reading FASTX record at position {}", num_pairs * 2)
+        })?;
+        num_pairs += 1;
+
+        let mate1_id = record1.id().to_vec();
+        let mate1 = select(record1.id(), num_pairs)?.then(|| FastxRecord::from(&record1));
+
+        let Some(result) = fastx_reader.next() else {
+            bail!(
+                "Interleaved input ends with an unpaired read: {} in {}",
+                String::from_utf8_lossy(read_id(&mate1_id)),
+                input.path().display()
+            );
+        };
+        let record2 = result.wrap_err_with(|| {
             format!(
-                "Error reading first FASTX record from {}",
-                file_path.display()
+                "Error reading FASTX record at position {}",
+                num_pairs * 2 - 1
             )
         })?;
+        if mate_id(&mate1_id) != mate_id(record2.id()) {
+            bail!(
+                "Mates of pair {num_pairs} in interleaved input {} have different read IDs: {} and {}",
+                input.path().display(),
+                String::from_utf8_lossy(read_id(&mate1_id)),
+                String::from_utf8_lossy(read_id(record2.id()))
+            );
+        }
+
+        if let Some(mate1) = mate1 {
+            send_pair(txs, [mate1, FastxRecord::from(&record2)])?;
+        }
+    }
+
+    Ok(num_pairs)
+}
 
-    Ok(record.format().into())
+// sends each mate to its own writer, or both to a single interleaved writer so they stay adjacent
+fn send_pair(txs: &[Sender<FastxRecord>], mates: [FastxRecord; 2]) -> Result<()> {
+    for (index, mate) in mates.into_iter().enumerate() {
+        txs[index.min(txs.len() - 1)]
+            .send(mate)
+            .wrap_err("Error sending record")?;
+    }
+    Ok(())
+}
+
+// reads the Kraken2 output line for the record(s) at `position`, checking each record has its read ID and returning
+// whether the read is selected. Kraken2 --paired drops the /1 or /2 of mates, so `paired` records are compared on
+// their mate ID
+fn next_ordered_read(
+    kraken: &mut OrderedKrakenOutput,
+    records: &[(&Path, &[u8])],
+    position: usize,
+    paired: bool,
+) -> Result<bool> {
+    let Some(kraken_read) = kraken.next_read()? else {
+        let (file_path, record_id) = records[0];
+        bail!(
+            "The Kraken2 output ended before read {} at position {position} of {}",
+            String::from_utf8_lossy(read_id(record_id)),
+            file_path.display()
+        );
+    };
+    for &(file_path, record_id) in records {
+        let matches = if paired {
+            mate_id(record_id) == mate_id(kraken_read.read_id)
+        } else {
+            read_id(record_id) == kraken_read.read_id
+        };
+        if !matches {
+            bail!(
+                "Read order mismatch at position {position}: {} has read {} but line {} of the Kraken2 output has read {}. Run without --ordered to match reads by ID",
+                file_path.display(),
+                String::from_utf8_lossy(read_id(record_id)),
+                kraken_read.line_number,
+                String::from_utf8_lossy(kraken_read.read_id)
+            );
+        }
+    }
+    Ok(kraken_read.selected)
+}
+
+fn ensure_kraken_output_ended(kraken: &mut OrderedKrakenOutput, file_path: &Path) -> Result<()> {
+    if let Some(kraken_read) = kraken.next_read()? {
+        bail!(
+            "The Kraken2 output has more reads than {}: read {} on line {} has no matching record",
+            file_path.display(),
+            String::from_utf8_lossy(kraken_read.read_id),
+            kraken_read.line_number
+        );
+    }
+    Ok(())
 }
 
 pub fn resolve_output_format(input: FastxFormat, requested: OutputFormat) -> FastxFormat {
@@ -139,19 +544,24 @@ pub fn write_output_fastx(
     };
 
     debug!("Output compression level specified as: {compression_level:?}");
-    debug!("Creating output file: {}", out_file.display());
 
-    if let Some(parent) = out_file.parent()
-        && !parent.as_os_str().is_empty()
-    {
-        fs::create_dir_all(parent)
-            .wrap_err_with(|| format!("Failed to create output directory: {}", parent.display()))?;
-    }
-
-    let out_file_handle = fs::File::create(out_file)
-        .wrap_err_with(|| format!("Failed to create output file: {}", out_file.display()))?;
+    let file_handle: Box<dyn Write> = if is_stdout(out_file) {
+        debug!("Writing output to stdout");
+        Box::new(io::BufWriter::new(io::stdout().lock()))
+    } else {
+        debug!("Creating output file: {}", out_file.display());
+        if let Some(parent) = out_file.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            fs::create_dir_all(parent).wrap_err_with(|| {
+                format!("Failed to create output directory: {}", parent.display())
+            })?;
+        }
 
-    let file_handle = Box::new(io::BufWriter::new(out_file_handle));
+        let out_file_handle = fs::File::create(out_file)
+            .wrap_err_with(|| format!("Failed to create output file: {}", out_file.display()))?;
+        Box::new(io::BufWriter::new(out_file_handle))
+    };
     let mut writer = niffler::get_writer(file_handle, compression_type, compression_level)
         .wrap_err("Failed to create niffler writer")?;
 
@@ -186,10 +596,16 @@ pub fn write_output_fastx(
 
         read_output_count += 1;
     }
+    writer.flush().wrap_err("Failed to flush output")?;
 
     Ok(read_output_count)
 }
 
+/// Whether the output is written to stdout rather than a file.
+pub fn is_stdout(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
 fn read_id(record_id: &[u8]) -> &[u8] {
     record_id
         .split(u8::is_ascii_whitespace)
@@ -197,6 +613,14 @@ fn read_id(record_id: &[u8]) -> &[u8] {
         .unwrap_or(record_id)
 }
 
+// the read ID without a trailing /1 or /2, which is shared by both mates of a pair
+fn mate_id(record_id: &[u8]) -> &[u8] {
+    let id = read_id(record_id);
+    id.strip_suffix(b"/1")
+        .or_else(|| id.strip_suffix(b"/2"))
+        .unwrap_or(id)
+}
+
 fn infer_compression(file_path: &Path) -> niffler::Format {
     match file_path.extension().and_then(|ext| ext.to_str()) {
         Some(ext) if ext.eq_ignore_ascii_case("gz") => niffler::Format::Gzip,
@@ -216,6 +640,7 @@ mod tests {
     use tempfile::tempdir;
 
     use super::*;
+    use crate::filter::ReadFilters;
 
     fn fastx_record(id: &str, seq: &str, qual: Option<&str>) -> FastxRecord {
         FastxRecord {
@@ -272,11 +697,13 @@ mod tests {
         let test_data = b"@read1\nAAAA\n+\n!!!!\n@read2\nGGGG\n+\n!!!!\n@read3\nTTTT\n+\n!!!!\n";
         let mut file = File::create(&file_path).unwrap();
         file.write_all(test_data).unwrap();
-        let mut reads_to_save = FxHashSet::default();
-        reads_to_save.insert(b"read1".to_vec());
-        reads_to_save.insert(b"read3".to_vec());
+        let mut reads_to_save = ReadSelection::default();
+        reads_to_save.keep(b"read1");
+        reads_to_save.keep(b"read3");
         let (tx, rx) = crossbeam::channel::unbounded();
-        let (read_count, input_format) = parse_fastx(&file_path, &reads_to_save, &tx).unwrap();
+        let mut input = FastxInput::open(&file_path).unwrap();
+        let input_format = input.format();
+        let read_count = parse_fastx(&mut input, &reads_to_save, &tx).unwrap();
         drop(tx);
         let results: Vec<FastxRecord> = rx.iter().collect();
 
@@ -297,10 +724,12 @@ mod tests {
             b"@read1 some description\nAAAA\n+\n!!!!\n@read2 another description\nGGGG\n+\n!!!!\n";
         let mut file = File::create(&file_path).unwrap();
         file.write_all(test_data).unwrap();
-        let mut reads_to_save = FxHashSet::default();
-        reads_to_save.insert(b"read1".to_vec());
+        let mut reads_to_save = ReadSelection::default();
+        reads_to_save.keep(b"read1");
         let (tx, rx) = crossbeam::channel::unbounded();
-        let (read_count, input_format) = parse_fastx(&file_path, &reads_to_save, &tx).unwrap();
+        let mut input = FastxInput::open(&file_path).unwrap();
+        let input_format = input.format();
+        let read_count = parse_fastx(&mut input, &reads_to_save, &tx).unwrap();
         drop(tx);
         let results: Vec<FastxRecord> = rx.iter().collect();
 
@@ -319,10 +748,12 @@ mod tests {
         let test_data = b">read1 some description\nAAAA\n>read2 another description\nGGGG\n";
         let mut file = File::create(&file_path).unwrap();
         file.write_all(test_data).unwrap();
-        let mut reads_to_save = FxHashSet::default();
-        reads_to_save.insert(b"read1".to_vec());
+        let mut reads_to_save = ReadSelection::default();
+        reads_to_save.keep(b"read1");
         let (tx, rx) = crossbeam::channel::unbounded();
-        let (read_count, input_format) = parse_fastx(&file_path, &reads_to_save, &tx).unwrap();
+        let mut input = FastxInput::open(&file_path).unwrap();
+        let input_format = input.format();
+        let read_count = parse_fastx(&mut input, &reads_to_save, &tx).unwrap();
         drop(tx);
         let results: Vec<FastxRecord> = rx.iter().collect();
 
@@ -335,11 +766,12 @@ mod tests {
     }
 
     #[test]
-    fn test_detect_fastx_format() {
+    fn test_fastx_input_format() {
         let dir = tempdir().unwrap();
         let fasta_path = dir.path().join("test.fasta");
         let fastq_path = dir.path().join("test.fastq");
         let empty_path = dir.path().join("empty.fastq");
+        let text_path = dir.path().join("test.txt");
         File::create(&fasta_path)
             .unwrap()
             .write_all(b">read1\nAAAA\n")
@@ -349,16 +781,75 @@ mod tests {
             .write_all(b"@read1\nAAAA\n+\n!!!!\n")
             .unwrap();
         File::create(&empty_path).unwrap();
+        File::create(&text_path)
+            .unwrap()
+            .write_all(b"not a sequence file\n")
+            .unwrap();
 
         assert_eq!(
-            detect_fastx_format(&fasta_path).unwrap(),
+            FastxInput::open(&fasta_path).unwrap().format(),
             FastxFormat::Fasta
         );
         assert_eq!(
-            detect_fastx_format(&fastq_path).unwrap(),
+            FastxInput::open(&fastq_path).unwrap().format(),
             FastxFormat::Fastq
         );
-        assert!(detect_fastx_format(&empty_path).is_err());
+        assert!(FastxInput::open(&empty_path).is_err());
+        assert!(FastxInput::open(&text_path).is_err());
+
+        // too short to detect compression, but still a record
+        let short_path = dir.path().join("short.fasta");
+        File::create(&short_path)
+            .unwrap()
+            .write_all(b">r\nA")
+            .unwrap();
+        assert_eq!(
+            FastxInput::open(&short_path).unwrap().format(),
+            FastxFormat::Fasta
+        );
+    }
+
+    #[test]
+    fn test_fastx_input_keeps_first_record() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("test.fastq.gz");
+        let mut writer = niffler::get_writer(
+            Box::new(File::create(&file_path).unwrap()),
+            niffler::Format::Gzip,
+            niffler::Level::One,
+        )
+        .unwrap();
+        writer
+            .write_all(b"@read1\nAAAA\n+\n!!!!\n@read2\nGGGG\n+\n!!!!\n")
+            .unwrap();
+        drop(writer);
+        let mut reads_to_save = ReadSelection::default();
+        reads_to_save.keep(b"read1");
+        reads_to_save.keep(b"read2");
+
+        let mut input = FastxInput::open(&file_path).unwrap();
+        let (tx, rx) = crossbeam::channel::unbounded();
+        // the first pass reads the stream opened for format detection, the second opens the file again
+        let first_pass = parse_fastx(&mut input, &reads_to_save, &tx).unwrap();
+        let second_pass = parse_fastx(&mut input, &reads_to_save, &tx).unwrap();
+        drop(tx);
+        let results: Vec<FastxRecord> = rx.iter().collect();
+
+        assert_eq!(input.format(), FastxFormat::Fastq);
+        assert_eq!((first_pass, second_pass), (2, 2));
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].id, b"read1");
+        assert_eq!(results[2].id, b"read1");
+    }
+
+    #[test]
+    fn test_is_fastx_stream() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("test.fastq");
+        File::create(&file_path).unwrap();
+
+        assert!(is_fastx_stream(Path::new("-")));
+        assert!(!is_fastx_stream(&file_path));
     }
 
     #[test]
@@ -368,11 +859,13 @@ mod tests {
         let test_data = b"@read1\nAAAA\n+\n!!!!\n@read2\nGGGG\n+\n!!!!\n@read3\nTTTT\n+\n!!!!\n";
         let mut file = File::create(&file_path).unwrap();
         file.write_all(test_data).unwrap();
-        let mut reads_to_save = FxHashSet::default();
-        reads_to_save.insert(b"read4".to_vec());
-        reads_to_save.insert(b"read5".to_vec());
+        let mut reads_to_save = ReadSelection::default();
+        reads_to_save.keep(b"read4");
+        reads_to_save.keep(b"read5");
         let (tx, rx) = crossbeam::channel::unbounded();
-        let (read_count, input_format) = parse_fastx(&file_path, &reads_to_save, &tx).unwrap();
+        let mut input = FastxInput::open(&file_path).unwrap();
+        let input_format = input.format();
+        let read_count = parse_fastx(&mut input, &reads_to_save, &tx).unwrap();
         drop(tx);
         let results: Vec<FastxRecord> = rx.iter().collect();
 
@@ -384,9 +877,10 @@ mod tests {
     #[test]
     fn test_parse_fastq_file_not_found() {
         let file_path = PathBuf::from("idontexist.fastq");
-        let reads_to_save = FxHashSet::default();
+        let reads_to_save = ReadSelection::default();
         let (tx, _rx) = crossbeam::channel::unbounded();
-        let result = parse_fastx(&file_path, &reads_to_save, &tx);
+        let result = FastxInput::open(&file_path)
+            .and_then(|mut input| parse_fastx(&mut input, &reads_to_save, &tx));
 
         assert!(result.is_err());
     }
@@ -591,4 +1085,356 @@ mod tests {
         assert_eq!(read_count, 1);
         assert!(subdir.exists());
     }
+
+    fn write_file(path: &Path, contents: &[u8]) {
+        File::create(path).unwrap().write_all(contents).unwrap();
+    }
+
+    #[test]
+    fn test_parse_fastx_ordered() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("test.fastq");
+        let kraken_path = dir.path().join("kraken.txt");
+        write_file(
+            &file_path,
+            b"@read1 desc\nAAAA\n+\n!!!!\n@read2\nGGGG\n+\n!!!!\n@read3\nTTTT\n+\n!!!!\n",
+        );
+        write_file(
+            &kraken_path,
+            b"C\tread1\t1337\t4\t1337:1\nC\tread2\t2\t4\t2:1\nC\tread3\t1337\t4\t1337:1\n",
+        );
+        let filters = ReadFilters::default();
+        let mut kraken = OrderedKrakenOutput::open(&kraken_path, false, &[1337], &filters).unwrap();
+        let (tx, rx) = crossbeam::channel::unbounded();
+        let mut input = FastxInput::open(&file_path).unwrap();
+        let read_count = parse_fastx_ordered(&mut input, &mut kraken, &tx).unwrap();
+        drop(tx);
+        let results: Vec<FastxRecord> = rx.iter().collect();
+
+        assert_eq!(read_count, 3);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].id, b"read1 desc");
+        assert_eq!(results[1].id, b"read3");
+        assert_eq!(kraken.finish().reads_per_taxon.get(&1337), Some(&2));
+    }
+
+    #[test]
+    fn test_parse_fastx_ordered_mismatch() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("test.fastq");
+        let kraken_path = dir.path().join("kraken.txt");
+        write_file(
+            &file_path,
+            b"@read1\nAAAA\n+\n!!!!\n@read2\nGGGG\n+\n!!!!\n",
+        );
+        write_file(
+            &kraken_path,
+            b"C\tread1\t1337\t4\t1337:1\nC\tread3\t1337\t4\t1337:1\n",
+        );
+        let filters = ReadFilters::default();
+        let mut kraken = OrderedKrakenOutput::open(&kraken_path, false, &[1337], &filters).unwrap();
+        let (tx, _rx) = crossbeam::channel::unbounded();
+        let err = parse_fastx_ordered(&mut FastxInput::open(&file_path).unwrap(), &mut kraken, &tx)
+            .unwrap_err();
+
+        let message = err.to_string();
+        assert!(message.contains("Read order mismatch at position 2"));
+        assert!(message.contains("has read read2 but line 2 of the Kraken2 output has read read3"));
+    }
+
+    #[test]
+    fn test_parse_fastx_ordered_different_lengths() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("test.fastq");
+        let short_kraken = dir.path().join("short.txt");
+        let long_kraken = dir.path().join("long.txt");
+        write_file(
+            &file_path,
+            b"@read1\nAAAA\n+\n!!!!\n@read2\nGGGG\n+\n!!!!\n",
+        );
+        write_file(&short_kraken, b"C\tread1\t1337\t4\t1337:1\n");
+        write_file(
+            &long_kraken,
+            b"C\tread1\t1337\t4\t1337:1\nC\tread2\t1337\t4\t1337:1\nC\tread3\t1337\t4\t1337:1\n",
+        );
+        let filters = ReadFilters::default();
+        let (tx, _rx) = crossbeam::channel::unbounded();
+
+        let mut kraken =
+            OrderedKrakenOutput::open(&short_kraken, false, &[1337], &filters).unwrap();
+        let err = parse_fastx_ordered(&mut FastxInput::open(&file_path).unwrap(), &mut kraken, &tx)
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("The Kraken2 output ended before read read2 at position 2")
+        );
+
+        let mut kraken = OrderedKrakenOutput::open(&long_kraken, false, &[1337], &filters).unwrap();
+        let err = parse_fastx_ordered(&mut FastxInput::open(&file_path).unwrap(), &mut kraken, &tx)
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("read read3 on line 3 has no matching record")
+        );
+    }
+
+    fn paired_inputs(file1: &Path, file2: &Path) -> [FastxInput; 2] {
+        [
+            FastxInput::open(file1).unwrap(),
+            FastxInput::open(file2).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn test_parse_paired_fastx_ordered() {
+        let dir = tempdir().unwrap();
+        let file1 = dir.path().join("test_R1.fastq");
+        let file2 = dir.path().join("test_R2.fastq");
+        let kraken_path = dir.path().join("kraken.txt");
+        write_file(&file1, b"@read1\nAAAA\n+\n!!!!\n@read2\nGGGG\n+\n!!!!\n");
+        write_file(&file2, b"@read1\nCCCC\n+\n!!!!\n@read2\nTTTT\n+\n!!!!\n");
+        write_file(
+            &kraken_path,
+            b"C\tread1\t2\t4|4\t2:1 |:| 2:1\nC\tread2\t1337\t4|4\t1337:1 |:| 1337:1\n",
+        );
+        let filters = ReadFilters::default();
+        let mut kraken = OrderedKrakenOutput::open(&kraken_path, false, &[1337], &filters).unwrap();
+        let (tx1, rx1) = crossbeam::channel::unbounded();
+        let (tx2, rx2) = crossbeam::channel::unbounded();
+        let pairs = parse_paired_fastx_ordered(
+            &mut paired_inputs(&file1, &file2),
+            &mut kraken,
+            &[tx1, tx2],
+        )
+        .unwrap();
+        let results1: Vec<FastxRecord> = rx1.iter().collect();
+        let results2: Vec<FastxRecord> = rx2.iter().collect();
+
+        assert_eq!(pairs, 2);
+        assert_eq!(results1, vec![fastx_record("read2", "GGGG", Some("!!!!"))]);
+        assert_eq!(results2, vec![fastx_record("read2", "TTTT", Some("!!!!"))]);
+    }
+
+    #[test]
+    fn test_parse_paired_fastx_ordered_mate_suffixes() {
+        let dir = tempdir().unwrap();
+        let file1 = dir.path().join("test_R1.fastq");
+        let file2 = dir.path().join("test_R2.fastq");
+        let kraken_path = dir.path().join("kraken.txt");
+        write_file(
+            &file1,
+            b"@read1/1\nAAAA\n+\n!!!!\n@read2/1\nGGGG\n+\n!!!!\n",
+        );
+        write_file(
+            &file2,
+            b"@read1/2\nCCCC\n+\n!!!!\n@read2/2\nTTTT\n+\n!!!!\n",
+        );
+        // Kraken2 --paired drops the mate suffix
+        write_file(
+            &kraken_path,
+            b"C\tread1\t2\t4|4\t2:1 |:| 2:1\nC\tread2\t1337\t4|4\t1337:1 |:| 1337:1\n",
+        );
+        let filters = ReadFilters::default();
+        let mut kraken = OrderedKrakenOutput::open(&kraken_path, false, &[1337], &filters).unwrap();
+        let (tx1, rx1) = crossbeam::channel::unbounded();
+        let (tx2, rx2) = crossbeam::channel::unbounded();
+        let pairs = parse_paired_fastx_ordered(
+            &mut paired_inputs(&file1, &file2),
+            &mut kraken,
+            &[tx1, tx2],
+        )
+        .unwrap();
+        let results1: Vec<FastxRecord> = rx1.iter().collect();
+        let results2: Vec<FastxRecord> = rx2.iter().collect();
+
+        assert_eq!(pairs, 2);
+        assert_eq!(
+            results1,
+            vec![fastx_record("read2/1", "GGGG", Some("!!!!"))]
+        );
+        assert_eq!(
+            results2,
+            vec![fastx_record("read2/2", "TTTT", Some("!!!!"))]
+        );
+    }
+
+    #[test]
+    fn test_parse_paired_fastx_ordered_mate_mismatch() {
+        let dir = tempdir().unwrap();
+        let file1 = dir.path().join("test_R1.fastq");
+        let file2 = dir.path().join("test_R2.fastq");
+        let kraken_path = dir.path().join("kraken.txt");
+        write_file(&file1, b"@read1\nAAAA\n+\n!!!!\n");
+        write_file(&file2, b"@read9\nCCCC\n+\n!!!!\n");
+        write_file(&kraken_path, b"C\tread1\t2\t4|4\t2:1 |:| 2:1\n");
+        let filters = ReadFilters::default();
+        let mut kraken = OrderedKrakenOutput::open(&kraken_path, false, &[1337], &filters).unwrap();
+        let (tx1, _rx1) = crossbeam::channel::unbounded();
+        let (tx2, _rx2) = crossbeam::channel::unbounded();
+        let err = parse_paired_fastx_ordered(
+            &mut paired_inputs(&file1, &file2),
+            &mut kraken,
+            &[tx1, tx2],
+        )
+        .unwrap_err();
+
+        assert!(err.to_string().contains("test_R2.fastq has read read9"));
+    }
+
+    #[test]
+    fn test_parse_interleaved_fastx() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("test.fastq");
+        write_file(
+            &file_path,
+            b"@read1/1\nAAAA\n+\n!!!!\n@read1/2\nCCCC\n+\n!!!!\n@read2 1:N\nGGGG\n+\n!!!!\n@read2 2:N\nTTTT\n+\n!!!!\n",
+        );
+        let mut reads_to_save = ReadSelection::default();
+        reads_to_save.keep(b"read2");
+        let (tx, rx) = crossbeam::channel::unbounded();
+        let pairs = parse_interleaved_fastx(
+            &mut FastxInput::open(&file_path).unwrap(),
+            &reads_to_save,
+            &[tx],
+        )
+        .unwrap();
+        let results: Vec<FastxRecord> = rx.iter().collect();
+
+        assert_eq!(pairs, 2);
+        assert_eq!(
+            results,
+            vec![
+                fastx_record("read2 1:N", "GGGG", Some("!!!!")),
+                fastx_record("read2 2:N", "TTTT", Some("!!!!")),
+            ]
+        );
+
+        // mates named with /1 and /2 are selected on the ID Kraken2 --paired writes, without the suffix
+        let mut reads_to_save = ReadSelection::default();
+        reads_to_save.keep(b"read1");
+        let (tx, rx) = crossbeam::channel::unbounded();
+        parse_interleaved_fastx(
+            &mut FastxInput::open(&file_path).unwrap(),
+            &reads_to_save,
+            &[tx],
+        )
+        .unwrap();
+        let results: Vec<FastxRecord> = rx.iter().collect();
+
+        assert_eq!(
+            results,
+            vec![
+                fastx_record("read1/1", "AAAA", Some("!!!!")),
+                fastx_record("read1/2", "CCCC", Some("!!!!")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_interleaved_fastx_mate_mismatch() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("test.fastq");
+        write_file(
+            &file_path,
+            b"@read1/1\nAAAA\n+\n!!!!\n@read2/2\nCCCC\n+\n!!!!\n",
+        );
+        let reads_to_save = ReadSelection::default();
+        let (tx, _rx) = crossbeam::channel::unbounded();
+        let err = parse_interleaved_fastx(
+            &mut FastxInput::open(&file_path).unwrap(),
+            &reads_to_save,
+            &[tx],
+        )
+        .unwrap_err();
+
+        assert!(
+            err.to_string()
+                .contains("different read IDs: read1/1 and read2/2")
+        );
+    }
+
+    #[test]
+    fn test_parse_interleaved_fastx_unpaired_read() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("test.fastq");
+        write_file(
+            &file_path,
+            b"@read1\nAAAA\n+\n!!!!\n@read1\nCCCC\n+\n!!!!\n@read2\nGGGG\n+\n!!!!\n",
+        );
+        let reads_to_save = ReadSelection::default();
+        let (tx, _rx) = crossbeam::channel::unbounded();
+        let err = parse_interleaved_fastx(
+            &mut FastxInput::open(&file_path).unwrap(),
+            &reads_to_save,
+            &[tx],
+        )
+        .unwrap_err();
+
+        assert!(err.to_string().contains("unpaired read: read2"));
+    }
+
+    #[test]
+    fn test_parse_interleaved_fastx_ordered() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("test.fastq");
+        let kraken_path = dir.path().join("kraken.txt");
+        write_file(
+            &file_path,
+            b"@read1\nAAAA\n+\n!!!!\n@read1\nCCCC\n+\n!!!!\n@read2\nGGGG\n+\n!!!!\n@read2\nTTTT\n+\n!!!!\n",
+        );
+        write_file(
+            &kraken_path,
+            b"C\tread1\t2\t4|4\t2:1 |:| 2:1\nC\tread2\t1337\t4|4\t1337:1 |:| 1337:1\n",
+        );
+        let filters = ReadFilters::default();
+        let mut kraken = OrderedKrakenOutput::open(&kraken_path, false, &[1337], &filters).unwrap();
+        let (tx1, rx1) = crossbeam::channel::unbounded();
+        let (tx2, rx2) = crossbeam::channel::unbounded();
+        let pairs = parse_interleaved_fastx_ordered(
+            &mut FastxInput::open(&file_path).unwrap(),
+            &mut kraken,
+            &[tx1, tx2],
+        )
+        .unwrap();
+        let results1: Vec<FastxRecord> = rx1.iter().collect();
+        let results2: Vec<FastxRecord> = rx2.iter().collect();
+
+        assert_eq!(pairs, 2);
+        assert_eq!(results1, vec![fastx_record("read2", "GGGG", Some("!!!!"))]);
+        assert_eq!(results2, vec![fastx_record("read2", "TTTT", Some("!!!!"))]);
+    }
+
+    #[test]
+    fn test_parse_interleaved_fastx_ordered_mate_suffixes() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("test.fastq");
+        let kraken_path = dir.path().join("kraken.txt");
+        write_file(
+            &file_path,
+            b"@read1/1\nAAAA\n+\n!!!!\n@read1/2\nCCCC\n+\n!!!!\n@read2/1\nGGGG\n+\n!!!!\n@read2/2\nTTTT\n+\n!!!!\n",
+        );
+        write_file(
+            &kraken_path,
+            b"C\tread1\t2\t4|4\t2:1 |:| 2:1\nC\tread2\t1337\t4|4\t1337:1 |:| 1337:1\n",
+        );
+        let filters = ReadFilters::default();
+        let mut kraken = OrderedKrakenOutput::open(&kraken_path, false, &[1337], &filters).unwrap();
+        let (tx, rx) = crossbeam::channel::unbounded();
+        let pairs = parse_interleaved_fastx_ordered(
+            &mut FastxInput::open(&file_path).unwrap(),
+            &mut kraken,
+            &[tx],
+        )
+        .unwrap();
+        let results: Vec<FastxRecord> = rx.iter().collect();
+
+        assert_eq!(pairs, 2);
+        assert_eq!(
+            results,
+            vec![
+                fastx_record("read2/1", "GGGG", Some("!!!!")),
+                fastx_record("read2/2", "TTTT", Some("!!!!"))
+            ]
+        );
+    }
 }
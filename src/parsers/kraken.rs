@@ -1,15 +1,26 @@
 use std::{
-    fs,
-    io::{BufRead, BufReader},
-    path::Path,
+    fmt, fs,
+    io::{self, BufRead, Read},
+    path::{Path, PathBuf},
+    process::ChildStdout,
+    str::FromStr,
+    sync::Mutex,
 };
 
 use color_eyre::{
-    Result,
+    Report, Result,
     eyre::{Context, bail, eyre},
 };
+use crossbeam::channel::{self, Receiver, Sender};
 use fxhash::{FxHashMap, FxHashSet};
-use log::{info, warn};
+use log::{debug, info, warn};
+use memchr::{memchr, memchr_iter, memmem};
+
+use crate::{
+    filter::{FilterStats, ReadFilters},
+    parsers::decompressed_reader,
+    read_ids::{ReadSelection, SpillWriter},
+};
 
 #[derive(Debug, Clone)]
 pub struct Tree {
@@ -17,6 +28,10 @@ pub struct Tree {
     pub level_num: usize,
     pub children: Vec<usize>,
     pub parent: Option<usize>,
+    /// The rank from the report, None for unclassified reads or a rank code outside the standard ranks.
+    pub rank: Option<Rank>,
+    /// The scientific name from the report, without its indentation.
+    pub name: String,
 }
 
 impl Tree {
@@ -26,21 +41,171 @@ impl Tree {
             level_num,
             children: Vec::new(),
             parent,
+            rank: None,
+            name: String::new(),
+        }
+    }
+}
+
+// the standard rank codes of a Kraken2 report, from the root down
+const STANDARD_RANKS: [char; 9] = ['R', 'D', 'K', 'P', 'C', 'O', 'F', 'G', 'S'];
+
+/// A rank code from the Kraken2 report. Ranks are ordered from the root down, and a numbered code is an intermediate
+/// rank that many levels below its standard rank, so `G < G1 < G2 < S < S1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Rank {
+    standard: usize,
+    depth: u32,
+}
+
+impl FromStr for Rank {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let unknown_rank =
+            || format!("Unknown rank: {s} Try a Kraken2 report rank code such as G, S or S1");
+        let code = s.trim();
+        let mut chars = code.chars();
+        let standard = chars
+            .next()
+            .and_then(|first| {
+                STANDARD_RANKS
+                    .iter()
+                    .position(|&rank| rank == first.to_ascii_uppercase())
+            })
+            .ok_or_else(unknown_rank)?;
+        let depth = match chars.as_str() {
+            "" => 0,
+            digits => digits.parse().map_err(|_| unknown_rank())?,
+        };
+        Ok(Self { standard, depth })
+    }
+}
+
+impl fmt::Display for Rank {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", STANDARD_RANKS[self.standard])?;
+        if self.depth > 0 {
+            write!(f, "{}", self.depth)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where `--parents` stops climbing the tree: at the ancestor of a given rank, or at a given ancestor taxon ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParentLimit {
+    Rank(Rank),
+    TaxonId(i32),
+}
+
+impl FromStr for ParentLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().parse::<i32>() {
+            Ok(taxon_id) => Ok(Self::TaxonId(taxon_id)),
+            Err(_) => s.parse().map(Self::Rank).map_err(|_| {
+                format!("Unknown rank or taxon ID: {s} Try a Kraken2 report rank code such as F or G, or a taxon ID")
+            }),
+        }
+    }
+}
+
+impl fmt::Display for ParentLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rank(rank) => write!(f, "{rank}"),
+            Self::TaxonId(taxon_id) => write!(f, "{taxon_id}"),
         }
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct KrakenRecord {
+pub struct KrakenRecord<'a> {
     pub is_classified: bool,
-    pub read_id: Vec<u8>,
+    pub read_id: &'a [u8],
     pub taxon_id: i32,
+    /// The scientific name of the taxon, present when Kraken2 was run with `--use-names`.
+    pub taxon_name: Option<&'a [u8]>,
+    pub length: SequenceLength,
+    /// The raw LCA k-mer map, only parsed when a filter needs it.
+    pub lca_map: &'a [u8],
 }
 
-#[derive(Debug, Clone)]
+/// The length column, written as `len1|len2` when Kraken2 was run with `--paired`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceLength {
+    Single(u32),
+    Paired(u32, u32),
+}
+
+/// The taxon a run of k-mers was assigned to in the LCA map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KmerTaxon {
+    /// `A:` entries, k-mers containing an ambiguous nucleotide.
+    Ambiguous,
+    Taxon(i32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KmerHit {
+    pub taxon: KmerTaxon,
+    pub count: u32,
+}
+
+/// A parsed LCA k-mer map. Paired-end maps keep both mates' hits in order, with the index where the
+/// second mate starts.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LcaMap {
+    hits: Vec<KmerHit>,
+    second_mate: Option<usize>,
+}
+
+impl LcaMap {
+    /// All k-mer hits of the read, or of both mates for paired-end reads.
+    pub fn hits(&self) -> &[KmerHit] {
+        &self.hits
+    }
+
+    /// The k-mer hits of each mate, or of the whole read for single-end reads.
+    pub fn mates(&self) -> impl Iterator<Item = &[KmerHit]> {
+        let (first, second) = match self.second_mate {
+            Some(index) => {
+                let (first, second) = self.hits.split_at(index);
+                (first, Some(second))
+            }
+            None => (self.hits.as_slice(), None),
+        };
+        std::iter::once(first).chain(second)
+    }
+
+    pub fn is_paired(&self) -> bool {
+        self.second_mate.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct ProcessedKrakenOutput {
-    pub reads_to_save: FxHashSet<Vec<u8>>,
+    pub reads_to_save: ReadSelection,
     pub reads_per_taxon: FxHashMap<i32, usize>,
+    /// Taxon names read from the output, only populated when Kraken2 was run with `--use-names`.
+    pub taxon_names: FxHashMap<i32, String>,
+    pub filter_stats: FilterStats,
+}
+
+impl ProcessedKrakenOutput {
+    /// Combines the partial results of two parser threads.
+    pub fn merge(&mut self, other: ProcessedKrakenOutput) {
+        self.reads_to_save.merge(other.reads_to_save);
+        for (taxon_id, reads) in other.reads_per_taxon {
+            *self.reads_per_taxon.entry(taxon_id).or_insert(0) += reads;
+        }
+        for (taxon_id, taxon_name) in other.taxon_names {
+            self.taxon_names.entry(taxon_id).or_insert(taxon_name);
+        }
+        self.filter_stats.merge(&other.filter_stats);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -65,40 +230,487 @@ pub fn process_kraken_output(
     kraken_path: &Path,
     exclude: bool,
     taxon_ids_to_save: &[i32],
+    filters: &ReadFilters,
+    threads: usize,
+    max_memory: Option<usize>,
 ) -> Result<ProcessedKrakenOutput> {
-    let taxon_ids_to_save: FxHashSet<i32> = taxon_ids_to_save.iter().copied().collect();
-    let mut reads_per_taxon: FxHashMap<i32, usize> = FxHashMap::default();
-    let mut reads_to_save = FxHashSet::default();
-    let kraken_file = fs::File::open(kraken_path).wrap_err_with(|| {
+    let mut reader = open_kraken_file(kraken_path).wrap_err_with(|| {
         format!(
             "Failed to open kraken output file: {}",
             kraken_path.display()
         )
     })?;
-    let reader = BufReader::new(kraken_file);
+    let parser = KrakenOutputParser {
+        exclude,
+        taxon_ids_to_save: taxon_ids_to_save.iter().copied().collect(),
+        filters,
+        chunk_size: CHUNK_SIZE,
+        max_memory,
+        spill: Mutex::new(None),
+    };
+
+    // in exclude mode most reads are often kept, so both sides are stored for the first lines to choose the smaller
+    // one to hold in memory
+    let mut processed = ProcessedKrakenOutput {
+        reads_to_save: if exclude {
+            ReadSelection::undecided()
+        } else {
+            ReadSelection::default()
+        },
+        ..Default::default()
+    };
+    let sampled_lines = if exclude {
+        parser.process_lines(&mut reader, &mut processed, 1, SELECTION_SAMPLE_LINES)?
+    } else {
+        0
+    };
+    processed.reads_to_save.choose_side();
+    if processed.reads_to_save.is_inverted() {
+        debug!("Storing the IDs of the reads to drop, as they are fewer than the reads to keep");
+    }
 
-    for (line_number, line_result) in reader.lines().enumerate() {
-        let line_number = line_number + 1;
-        let line = line_result
-            .wrap_err_with(|| format!("Error reading kraken output line {line_number}"))?;
-        let record = process_kraken_output_line(&line).wrap_err_with(|| {
-            format!("Failed to parse kraken output at line {line_number}: {line}")
+    if threads > 1 {
+        let remaining = parser.process_chunks(
+            reader,
+            sampled_lines + 1,
+            threads,
+            processed.reads_to_save.is_inverted(),
+        )?;
+        processed.merge(remaining);
+    } else {
+        parser.process_lines(&mut reader, &mut processed, sampled_lines + 1, usize::MAX)?;
+    }
+
+    let spill = parser
+        .spill
+        .into_inner()
+        .map_err(|_| eyre!("Kraken output parser thread panicked while spilling read IDs"))?;
+    if let (Some(mut spill), Some(max_memory)) = (spill, max_memory) {
+        spill.write(&processed.reads_to_save.take_ids())?;
+        let spilled_ids = spill.len();
+        processed
+            .reads_to_save
+            .set_spilled(spill.finish(max_memory)?);
+        info!(
+            "Spilled {spilled_ids} read IDs to disk to stay within --max-memory, the input will be read in {} passes",
+            processed.reads_to_save.passes()
+        );
+    } else {
+        processed.reads_to_save.shrink_to_fit();
+    }
+    Ok(processed)
+}
+
+// lines are handed to the parser threads in newline-aligned chunks of roughly this many bytes
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+// lines parsed before choosing which side of the selection to store in exclude mode
+const SELECTION_SAMPLE_LINES: usize = 1_000_000;
+
+struct Chunk {
+    first_line_number: usize,
+    data: Vec<u8>,
+}
+
+struct KrakenOutputParser<'a> {
+    exclude: bool,
+    taxon_ids_to_save: FxHashSet<i32>,
+    filters: &'a ReadFilters,
+    chunk_size: usize,
+    max_memory: Option<usize>,
+    // created the first time the selected read IDs outgrow `max_memory`, and shared by every parser thread
+    spill: Mutex<Option<SpillWriter>>,
+}
+
+impl KrakenOutputParser<'_> {
+    // parses up to `max_lines` lines, returning the number parsed
+    fn process_lines(
+        &self,
+        reader: &mut impl BufRead,
+        processed: &mut ProcessedKrakenOutput,
+        first_line_number: usize,
+        max_lines: usize,
+    ) -> Result<usize> {
+        // a single line buffer is reused for the whole file, and fields are borrowed from it
+        let mut buffer = Vec::new();
+        let mut lines = 0;
+        while lines < max_lines {
+            let line_number = first_line_number + lines;
+            buffer.clear();
+            let bytes_read = reader
+                .read_until(b'\n', &mut buffer)
+                .wrap_err_with(|| format!("Error reading kraken output line {line_number}"))?;
+            if bytes_read == 0 {
+                break;
+            }
+            self.process_line(processed, trim_line_ending(&buffer), line_number)?;
+            self.spill_over_limit(processed, self.max_memory)?;
+            lines += 1;
+        }
+        Ok(lines)
+    }
+
+    // the calling thread reads chunks while `threads` workers parse them into partial results, which are merged
+    // once the whole file has been read
+    fn process_chunks(
+        &self,
+        reader: impl BufRead,
+        first_line_number: usize,
+        threads: usize,
+        inverted: bool,
+    ) -> Result<ProcessedKrakenOutput> {
+        let partial = || ProcessedKrakenOutput {
+            reads_to_save: ReadSelection::storing(inverted),
+            ..Default::default()
+        };
+        std::thread::scope(|scope| {
+            let (chunk_tx, chunk_rx) = channel::bounded::<Chunk>(threads * 2);
+            // emptied chunk buffers are sent back to the reader to be reused
+            let (buffer_tx, buffer_rx) = channel::unbounded::<Vec<u8>>();
+
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    let chunk_rx = chunk_rx.clone();
+                    let buffer_tx = buffer_tx.clone();
+                    // a failing worker stops at its first error, tagged with the line its chunk starts at
+                    scope.spawn(move || -> Result<ProcessedKrakenOutput, (usize, Report)> {
+                        let mut partial = partial();
+                        // each thread gets an equal share of the memory limit
+                        let max_memory = self.max_memory.map(|max_memory| max_memory / threads);
+                        for chunk in chunk_rx {
+                            self.process_chunk(&mut partial, &chunk)
+                                .and_then(|()| self.spill_over_limit(&mut partial, max_memory))
+                                .map_err(|err| (chunk.first_line_number, err))?;
+                            let _ = buffer_tx.send(chunk.data);
+                        }
+                        Ok(partial)
+                    })
+                })
+                .collect();
+            drop(chunk_rx);
+            drop(buffer_tx);
+
+            let read_result = self.read_chunks(reader, first_line_number, &chunk_tx, &buffer_rx);
+            drop(chunk_tx);
+
+            // chunks are handed out in order, so every chunk before the first failing one was fully parsed,
+            // and reporting the error with the smallest line number gives the same error on every run
+            let mut processed = partial();
+            let mut first_error: Option<(usize, Report)> = None;
+            for worker in workers {
+                match worker
+                    .join()
+                    .map_err(|_| eyre!("Kraken output parser thread panicked"))?
+                {
+                    Ok(partial) => processed.merge(partial),
+                    Err((line_number, err)) => {
+                        if first_error
+                            .as_ref()
+                            .is_none_or(|&(first_line_number, _)| line_number < first_line_number)
+                        {
+                            first_error = Some((line_number, err));
+                        }
+                    }
+                }
+            }
+            if let Some((_, err)) = first_error {
+                return Err(err);
+            }
+            read_result?;
+            Ok(processed)
+        })
+    }
+
+    fn read_chunks(
+        &self,
+        mut reader: impl BufRead,
+        mut first_line_number: usize,
+        chunk_tx: &Sender<Chunk>,
+        buffer_rx: &Receiver<Vec<u8>>,
+    ) -> Result<()> {
+        loop {
+            let mut data = buffer_rx.try_recv().unwrap_or_default();
+            data.clear();
+            reader
+                .by_ref()
+                .take(self.chunk_size as u64)
+                .read_to_end(&mut data)
+                .and_then(|_| {
+                    // extend the chunk to the end of its last line
+                    if data.last().is_some_and(|&byte| byte != b'\n') {
+                        reader.read_until(b'\n', &mut data)?;
+                    }
+                    Ok(())
+                })
+                .wrap_err_with(|| {
+                    format!("Error reading kraken output from line {first_line_number}")
+                })?;
+            if data.is_empty() {
+                return Ok(());
+            }
+            let lines =
+                memchr_iter(b'\n', &data).count() + usize::from(data.last() != Some(&b'\n'));
+            // every worker has stopped on an error, which is reported when they are joined
+            if chunk_tx
+                .send(Chunk {
+                    first_line_number,
+                    data,
+                })
+                .is_err()
+            {
+                return Ok(());
+            }
+            first_line_number += lines;
+        }
+    }
+
+    // writes the stored read IDs to disk once they use more than `max_memory` bytes
+    fn spill_over_limit(
+        &self,
+        processed: &mut ProcessedKrakenOutput,
+        max_memory: Option<usize>,
+    ) -> Result<()> {
+        let Some(max_memory) = max_memory else {
+            return Ok(());
+        };
+        if !processed.reads_to_save.can_spill()
+            || processed.reads_to_save.memory_usage() <= max_memory
+        {
+            return Ok(());
+        }
+        let read_ids = processed.reads_to_save.take_ids();
+        let mut spill = self
+            .spill
+            .lock()
+            .map_err(|_| eyre!("Kraken output parser thread panicked while spilling read IDs"))?;
+        if spill.is_none() {
+            debug!("Selected read IDs exceed --max-memory, spilling them to disk");
+            *spill = Some(SpillWriter::new()?);
+        }
+        spill
+            .as_mut()
+            .map_or(Ok(()), |spill| spill.write(&read_ids))
+    }
+
+    fn process_chunk(&self, processed: &mut ProcessedKrakenOutput, chunk: &Chunk) -> Result<()> {
+        let data = chunk.data.strip_suffix(b"\n").unwrap_or(&chunk.data);
+        for (index, line) in split_bytes(data, b'\n').enumerate() {
+            self.process_line(
+                processed,
+                trim_line_ending(line),
+                chunk.first_line_number + index,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn process_line(
+        &self,
+        processed: &mut ProcessedKrakenOutput,
+        line: &[u8],
+        line_number: usize,
+    ) -> Result<()> {
+        match self.decide_line(processed, line, line_number)? {
+            (read_id, Some(_)) => processed.reads_to_save.keep(read_id),
+            (read_id, None) => processed.reads_to_save.reject(read_id),
+        }
+        Ok(())
+    }
+
+    // parses a line and applies the filters, returning the read ID and the taxon it is extracted for, if any
+    fn decide_line<'l>(
+        &self,
+        processed: &mut ProcessedKrakenOutput,
+        line: &'l [u8],
+        line_number: usize,
+    ) -> Result<(&'l [u8], Option<i32>)> {
+        let record = process_kraken_output_line(line).wrap_err_with(|| {
+            format!(
+                "Failed to parse kraken output at line {line_number}: {}",
+                String::from_utf8_lossy(line)
+            )
         })?;
-        if (exclude && !taxon_ids_to_save.contains(&record.taxon_id))
-            || (!exclude && taxon_ids_to_save.contains(&record.taxon_id))
+        if let Some(taxon_name) = record.taxon_name
+            && !processed.taxon_names.contains_key(&record.taxon_id)
         {
-            reads_to_save.insert(record.read_id);
-            *reads_per_taxon.entry(record.taxon_id).or_insert(0) += 1;
+            processed.taxon_names.insert(
+                record.taxon_id,
+                String::from_utf8_lossy(taxon_name).into_owned(),
+            );
+        }
+        let taxon_id = self
+            .filters
+            .apply(
+                &record,
+                &self.taxon_ids_to_save,
+                self.exclude,
+                &mut processed.filter_stats,
+            )
+            .wrap_err_with(|| {
+                format!(
+                    "Failed to apply read filters at line {line_number}: {}",
+                    String::from_utf8_lossy(line)
+                )
+            })?;
+        if let Some(taxon_id) = taxon_id {
+            *processed.reads_per_taxon.entry(taxon_id).or_insert(0) += 1;
+        }
+        Ok((record.read_id, taxon_id))
+    }
+}
+
+/// A line of Kraken2 output read by [`OrderedKrakenOutput`].
+pub struct OrderedRead<'a> {
+    pub read_id: &'a [u8],
+    pub line_number: usize,
+    /// Whether the read is extracted.
+    pub selected: bool,
+}
+
+/// Kraken2 output read one line at a time for `--ordered` extraction. Each read is decided as it is reached, so the
+/// IDs of the reads to extract are never collected.
+pub struct OrderedKrakenOutput<'a> {
+    reader: Box<dyn BufRead + Send>,
+    parser: KrakenOutputParser<'a>,
+    buffer: Vec<u8>,
+    line_number: usize,
+    processed: ProcessedKrakenOutput,
+}
+
+impl<'a> OrderedKrakenOutput<'a> {
+    pub fn open(
+        kraken_path: &Path,
+        exclude: bool,
+        taxon_ids_to_save: &[i32],
+        filters: &'a ReadFilters,
+    ) -> Result<Self> {
+        let reader = open_kraken_file(kraken_path).wrap_err_with(|| {
+            format!(
+                "Failed to open kraken output file: {}",
+                kraken_path.display()
+            )
+        })?;
+        Ok(Self::new(reader, exclude, taxon_ids_to_save, filters))
+    }
+
+    pub fn new(
+        reader: Box<dyn BufRead + Send>,
+        exclude: bool,
+        taxon_ids_to_save: &[i32],
+        filters: &'a ReadFilters,
+    ) -> Self {
+        Self {
+            reader,
+            parser: KrakenOutputParser {
+                exclude,
+                taxon_ids_to_save: taxon_ids_to_save.iter().copied().collect(),
+                filters,
+                chunk_size: CHUNK_SIZE,
+                max_memory: None,
+                spill: Mutex::new(None),
+            },
+            buffer: Vec::new(),
+            line_number: 0,
+            processed: ProcessedKrakenOutput::default(),
+        }
+    }
+
+    /// Parses the next line, returning `None` at the end of the output.
+    pub fn next_read(&mut self) -> Result<Option<OrderedRead<'_>>> {
+        let line_number = self.line_number + 1;
+        self.buffer.clear();
+        let bytes_read = self
+            .reader
+            .read_until(b'\n', &mut self.buffer)
+            .wrap_err_with(|| format!("Error reading kraken output line {line_number}"))?;
+        if bytes_read == 0 {
+            return Ok(None);
+        }
+        self.line_number = line_number;
+        let (read_id, taxon_id) = self.parser.decide_line(
+            &mut self.processed,
+            trim_line_ending(&self.buffer),
+            line_number,
+        )?;
+        Ok(Some(OrderedRead {
+            read_id,
+            line_number,
+            selected: taxon_id.is_some(),
+        }))
+    }
+
+    /// The per-taxon counts, taxon names and filter statistics of the lines read. The read selection is left empty.
+    pub fn finish(self) -> ProcessedKrakenOutput {
+        self.processed
+    }
+}
+
+/// Opens a Kraken2 output or report file, or stdin if the path is `-`. Compressed input (gz, bz2) is
+/// detected from its content, the same way as for the FASTX input.
+pub fn open_kraken_file(path: &Path) -> Result<Box<dyn BufRead + Send>> {
+    let stream: Box<dyn Read + Send> = if path.as_os_str() == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(fs::File::open(path)?)
+    };
+    decompressed_reader(stream)
+}
+
+/// Where the Kraken2 output is read from.
+pub enum KrakenSource {
+    /// A file path, or `-` for stdin.
+    Path(PathBuf),
+    /// The output of a Kraken2 process started by kractor.
+    Process(ChildStdout),
+}
+
+impl KrakenSource {
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Self::Path(path) => Some(path),
+            Self::Process(_) => None,
         }
     }
-    Ok(ProcessedKrakenOutput {
-        reads_to_save,
-        reads_per_taxon,
+
+    pub fn open(self) -> Result<Box<dyn BufRead + Send>> {
+        match self {
+            Self::Path(path) => open_kraken_file(&path)
+                .wrap_err_with(|| format!("Failed to open kraken output file: {}", path.display())),
+            Self::Process(stdout) => decompressed_reader(Box::new(stdout)),
+        }
+    }
+}
+
+/// Whether the Kraken2 output is read from stdin or a named pipe, where lines arrive while Kraken2 is running.
+pub fn is_kraken_pipe(path: &Path) -> bool {
+    path.as_os_str() == "-" || fs::metadata(path).is_ok_and(|metadata| !metadata.is_file())
+}
+
+fn trim_line_ending(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+// splits on a delimiter with memchr, without validating the bytes as UTF-8
+fn split_bytes(bytes: &[u8], delimiter: u8) -> impl Iterator<Item = &[u8]> {
+    let mut rest = Some(bytes);
+    std::iter::from_fn(move || {
+        let field = rest?;
+        match memchr(delimiter, field) {
+            Some(index) => {
+                rest = Some(&field[index + 1..]);
+                Some(&field[..index])
+            }
+            None => {
+                rest = None;
+                Some(field)
+            }
+        }
     })
 }
 
-fn process_kraken_output_line(kraken_output: &str) -> Result<KrakenRecord> {
-    let mut fields = kraken_output.split('\t');
+fn process_kraken_output_line(kraken_output: &[u8]) -> Result<KrakenRecord<'_>> {
+    let mut fields = split_bytes(kraken_output, b'\t');
 
     let classification = fields
         .next()
@@ -109,29 +721,128 @@ fn process_kraken_output_line(kraken_output: &str) -> Result<KrakenRecord> {
     let taxon_id = fields
         .next()
         .ok_or_else(|| eyre!("Missing taxon ID field"))?;
-    let _length = fields
+    let length = fields
         .next()
         .ok_or_else(|| eyre!("Missing length field in the kraken output file"))?;
-    let _lca_map = fields
+    let lca_map = fields
         .next()
         .ok_or_else(|| eyre!("Missing LCA map in the kraken output file"))?;
 
     if fields.next().is_none() {
-        let is_classified = classification == "C";
-        let taxon_id = taxon_id
-            .trim()
-            .parse::<i32>()
-            .wrap_err_with(|| format!("Error parsing taxon ID: '{taxon_id}'"))?;
+        let is_classified = classification == b"C";
+        let (taxon_id, taxon_name) = parse_taxon_field(taxon_id)?;
+        let length = parse_sequence_length(length)?;
         Ok(KrakenRecord {
             is_classified,
-            read_id: read_id.as_bytes().to_vec(),
+            read_id,
             taxon_id,
+            taxon_name,
+            length,
+            lca_map,
         })
     } else {
         bail!("Invalid kraken output line format: Expected 5 tab-separated fields, but got more");
     }
 }
 
+// the taxon column is either a bare taxon ID, or `Escherichia coli (taxid 562)` with `--use-names`
+fn parse_taxon_field(taxon: &[u8]) -> Result<(i32, Option<&[u8]>)> {
+    let taxon = taxon.trim_ascii();
+    let (taxon_id, taxon_name) = match taxon.strip_suffix(b")").and_then(|taxon| {
+        memmem::rfind(taxon, TAXID_MARKER)
+            .map(|index| (&taxon[index + TAXID_MARKER.len()..], &taxon[..index]))
+    }) {
+        Some((taxon_id, name)) => (taxon_id, Some(name)),
+        None => (taxon, None),
+    };
+    let taxon_id = parse_i32(taxon_id).ok_or_else(|| {
+        eyre!(
+            "Error parsing taxon ID: '{}'",
+            String::from_utf8_lossy(taxon)
+        )
+    })?;
+    Ok((taxon_id, taxon_name))
+}
+
+const TAXID_MARKER: &[u8] = b" (taxid ";
+
+fn parse_sequence_length(length: &[u8]) -> Result<SequenceLength> {
+    let parse = |value: &[u8]| {
+        parse_u32(value.trim_ascii()).ok_or_else(|| {
+            eyre!(
+                "Error parsing sequence length: '{}'",
+                String::from_utf8_lossy(length)
+            )
+        })
+    };
+    match memchr(b'|', length) {
+        Some(index) => Ok(SequenceLength::Paired(
+            parse(&length[..index])?,
+            parse(&length[index + 1..])?,
+        )),
+        None => Ok(SequenceLength::Single(parse(length)?)),
+    }
+}
+
+pub fn parse_lca_map(lca_map: &[u8]) -> Result<LcaMap> {
+    let mut hits = Vec::new();
+    let mut second_mate = None;
+    for entry in lca_map
+        .split(u8::is_ascii_whitespace)
+        .filter(|entry| !entry.is_empty())
+    {
+        // paired-end output separates the mates with a `|:|` marker
+        if entry == b"|:|" {
+            if second_mate.is_some() {
+                bail!("Invalid LCA map: more than one '|:|' mate separator");
+            }
+            second_mate = Some(hits.len());
+            continue;
+        }
+        let invalid_entry = |message: &str| {
+            eyre!(
+                "{message} in LCA map entry: '{}'",
+                String::from_utf8_lossy(entry)
+            )
+        };
+        let separator = memchr(b':', entry).ok_or_else(|| invalid_entry("Missing k-mer count"))?;
+        let (taxon, count) = (&entry[..separator], &entry[separator + 1..]);
+        let count = parse_u32(count).ok_or_else(|| invalid_entry("Error parsing k-mer count"))?;
+        let taxon = if taxon == b"A" {
+            KmerTaxon::Ambiguous
+        } else {
+            KmerTaxon::Taxon(
+                parse_i32(taxon).ok_or_else(|| invalid_entry("Error parsing taxon ID"))?,
+            )
+        };
+        hits.push(KmerHit { taxon, count });
+    }
+    Ok(LcaMap { hits, second_mate })
+}
+
+// parses an unsigned decimal straight from the bytes, returning None on overflow or a non-digit
+fn parse_u32(digits: &[u8]) -> Option<u32> {
+    if digits.is_empty() {
+        return None;
+    }
+    digits.iter().try_fold(0u32, |value, &byte| {
+        let digit = byte.wrapping_sub(b'0');
+        if digit > 9 {
+            return None;
+        }
+        value.checked_mul(10)?.checked_add(u32::from(digit))
+    })
+}
+
+fn parse_i32(digits: &[u8]) -> Option<i32> {
+    match digits.split_first() {
+        Some((b'-', digits)) => parse_u32(digits)
+            .and_then(|value| i32::try_from(value).ok())
+            .map(|value| -value),
+        _ => parse_u32(digits).and_then(|value| i32::try_from(value).ok()),
+    }
+}
+
 pub fn build_tree_from_kraken_report(
     taxon_to_save: &[i32],
     report_path: &Path,
@@ -143,14 +854,12 @@ pub fn build_tree_from_kraken_report(
     // taxonid -> index in the nodes vector
     let mut taxon_map = FxHashMap::default();
 
-    let report_file = fs::File::open(report_path).wrap_err_with(|| {
+    let reader = open_kraken_file(report_path).wrap_err_with(|| {
         format!(
             "Failed to open kraken report file: {}",
             report_path.display()
         )
     })?;
-
-    let reader = BufReader::new(report_file);
     let mut prev_index = None;
 
     for (line_number, line_result) in reader.lines().enumerate() {
@@ -176,7 +885,11 @@ pub fn build_tree_from_kraken_report(
         }
         // 1 will be the root of the tree
         if record.taxon_id == 1 {
-            let root_node = Tree::new(record.taxon_id, record.level, None);
+            let root_node = Tree {
+                rank: record.rank.parse().ok(),
+                name: record.name.trim().to_string(),
+                ..Tree::new(record.taxon_id, record.level, None)
+            };
             prev_index = Some(nodes.len());
             nodes.push(root_node);
             continue;
@@ -189,7 +902,11 @@ pub fn build_tree_from_kraken_report(
             prev_index = nodes[parent_index].parent;
         }
         // once we have the correct parent, we can add the current node to the tree
-        let curr_node = Tree::new(record.taxon_id, record.level, prev_index);
+        let curr_node = Tree {
+            rank: record.rank.parse().ok(),
+            name: record.name.trim().to_string(),
+            ..Tree::new(record.taxon_id, record.level, prev_index)
+        };
         let curr_index = nodes.len();
         nodes.push(curr_node);
 
@@ -298,8 +1015,9 @@ pub fn extract_parents(
     taxon_map: &FxHashMap<i32, usize>,
     nodes: &[Tree],
     taxon_id: i32,
+    limit: Option<ParentLimit>,
 ) -> Result<Vec<i32>> {
-    // Backtracking traversal from the given taxon_id to the root
+    // Backtracking traversal from the given taxon_id to the root, or to the limit
 
     let &start_index = taxon_map
         .get(&taxon_id)
@@ -307,16 +1025,48 @@ pub fn extract_parents(
 
     let mut parents = Vec::new();
     parents.push(taxon_id);
+    if limit == Some(ParentLimit::TaxonId(taxon_id)) {
+        return Ok(parents);
+    }
     let mut curr_index = start_index;
 
     while let Some(parent_index) = nodes[curr_index].parent {
-        parents.push(nodes[parent_index].taxon_id);
+        let parent = &nodes[parent_index];
+        // ranks above the limit end the climb, while ancestors without a standard rank are passed through
+        if let Some(ParentLimit::Rank(max_rank)) = limit
+            && parent.rank.is_some_and(|rank| rank < max_rank)
+        {
+            break;
+        }
+        parents.push(parent.taxon_id);
+        if limit == Some(ParentLimit::TaxonId(parent.taxon_id)) {
+            return Ok(parents);
+        }
         curr_index = parent_index;
     }
 
+    if let Some(ParentLimit::TaxonId(limit_id)) = limit {
+        bail!(
+            "taxon ID {limit_id} given to --parents-up-to is not an ancestor of taxon ID {taxon_id}"
+        );
+    }
     Ok(parents)
 }
 
+/// Walks up from a node, itself included, to the first node at exactly the given rank.
+pub fn find_ancestor_at_rank(nodes: &[Tree], start_index: usize, rank: Rank) -> Option<usize> {
+    let mut curr_index = Some(start_index);
+    while let Some(index) = curr_index {
+        match nodes[index].rank {
+            Some(node_rank) if node_rank == rank => return Some(index),
+            // past the rank, as ranks only get broader towards the root
+            Some(node_rank) if node_rank < rank => return None,
+            _ => curr_index = nodes[index].parent,
+        }
+    }
+    None
+}
+
 pub fn extract_children(nodes: &[Tree], result: &mut Vec<i32>, start_index: usize) -> Result<()> {
     // recursive post-order traversal of the tree
     for &child_index in &nodes[start_index].children {
@@ -334,12 +1084,13 @@ mod tests {
     use tempfile::tempdir;
 
     use super::*;
+    use crate::filter::StrictExclusion;
 
     // kraken output tests
     #[test]
     fn test_process_kraken_output_line_valid() {
         let line = "C\tread_123\t1337\t150\t0:1 1:10";
-        let result = process_kraken_output_line(line).unwrap();
+        let result = process_kraken_output_line(line.as_bytes()).unwrap();
         assert!(result.is_classified);
         assert_eq!(result.read_id, b"read_123");
         assert_eq!(result.taxon_id, 1337);
@@ -348,7 +1099,7 @@ mod tests {
     #[test]
     fn test_process_kraken_output_line_unclassified() {
         let line = "U\tread_123\t1337\t150\t0:1 1:10";
-        let result = process_kraken_output_line(line).unwrap();
+        let result = process_kraken_output_line(line.as_bytes()).unwrap();
         assert!(!result.is_classified);
         assert_eq!(result.read_id, b"read_123");
         assert_eq!(result.taxon_id, 1337);
@@ -357,27 +1108,175 @@ mod tests {
     #[test]
     fn test_process_kraken_output_line_missing_fields() {
         let line = "";
-        assert!(process_kraken_output_line(line).is_err());
+        assert!(process_kraken_output_line(line.as_bytes()).is_err());
         let line = "C";
-        assert!(process_kraken_output_line(line).is_err());
+        assert!(process_kraken_output_line(line.as_bytes()).is_err());
         let line = "C\tread_123";
-        assert!(process_kraken_output_line(line).is_err());
+        assert!(process_kraken_output_line(line.as_bytes()).is_err());
         let line = "C\tread_123\t1337";
-        assert!(process_kraken_output_line(line).is_err());
+        assert!(process_kraken_output_line(line.as_bytes()).is_err());
         let line = "C\tread_123\t1337\t150";
-        assert!(process_kraken_output_line(line).is_err());
+        assert!(process_kraken_output_line(line.as_bytes()).is_err());
     }
 
     #[test]
     fn test_process_kraken_output_line_invalid_taxon_id() {
         let line = "C\tread_123\tAAAA\t150\t0:1 1:10";
-        assert!(process_kraken_output_line(line).is_err());
+        assert!(process_kraken_output_line(line.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_process_kraken_output_line_use_names() {
+        let line = "C\tread_123\tEscherichia coli (taxid 562)\t150\t562:10";
+        let result = process_kraken_output_line(line.as_bytes()).unwrap();
+        assert_eq!(result.taxon_id, 562);
+        assert_eq!(result.taxon_name, Some(b"Escherichia coli".as_slice()));
+
+        let line = "C\tread_123\tEscherichia coli O157:H7 (EHEC) (taxid 83334)\t150\t562:10";
+        let result = process_kraken_output_line(line.as_bytes()).unwrap();
+        assert_eq!(result.taxon_id, 83334);
+        assert_eq!(
+            result.taxon_name,
+            Some(b"Escherichia coli O157:H7 (EHEC)".as_slice())
+        );
+
+        let line = "U\tread_123\tunclassified (taxid 0)\t150\t0:10";
+        let result = process_kraken_output_line(line.as_bytes()).unwrap();
+        assert_eq!(result.taxon_id, 0);
+        assert_eq!(result.taxon_name, Some(b"unclassified".as_slice()));
+
+        let line = "C\tread_123\t562\t150\t562:10";
+        assert_eq!(
+            process_kraken_output_line(line.as_bytes())
+                .unwrap()
+                .taxon_name,
+            None
+        );
+
+        let line = "C\tread_123\tEscherichia coli (taxid abc)\t150\t562:10";
+        assert!(process_kraken_output_line(line.as_bytes()).is_err());
+        let line = "C\tread_123\tEscherichia coli\t150\t562:10";
+        assert!(process_kraken_output_line(line.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_process_kraken_output_use_names() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("kraken_output.txt");
+        let test_data = "C\tread_1\tEscherichia coli (taxid 562)\t150\t562:10
+C\tread_2\tSalmonella enterica (taxid 28901)\t150\t28901:10
+U\tread_3\tunclassified (taxid 0)\t150\t0:10
+C\tread_4\tEscherichia coli (taxid 562)\t150\t562:10";
+        let mut file = File::create(&file_path).unwrap();
+        file.write_all(test_data.as_bytes()).unwrap();
+        let ProcessedKrakenOutput {
+            reads_to_save,
+            reads_per_taxon,
+            taxon_names,
+            ..
+        } = process_kraken_output(&file_path, false, &[562], &ReadFilters::default(), 1, None)
+            .unwrap();
+        assert_eq!(reads_to_save.len(), 2);
+        assert_eq!(reads_per_taxon[&562], 2);
+        assert_eq!(taxon_names[&562], "Escherichia coli");
+        assert_eq!(taxon_names[&28901], "Salmonella enterica");
+        assert_eq!(taxon_names[&0], "unclassified");
     }
 
     #[test]
     fn test_process_kraken_output_line_too_many_fields() {
         let line = "C\tread_123\t9606\t150\t0:1 1:10\tishouldntbehere";
-        assert!(process_kraken_output_line(line).is_err());
+        assert!(process_kraken_output_line(line.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_parse_lca_map() {
+        let lca_map = parse_lca_map(b"562:13 A:4 0:1 561:2").unwrap();
+        assert!(!lca_map.is_paired());
+        assert_eq!(
+            lca_map.hits(),
+            vec![
+                KmerHit {
+                    taxon: KmerTaxon::Taxon(562),
+                    count: 13
+                },
+                KmerHit {
+                    taxon: KmerTaxon::Ambiguous,
+                    count: 4
+                },
+                KmerHit {
+                    taxon: KmerTaxon::Taxon(0),
+                    count: 1
+                },
+                KmerHit {
+                    taxon: KmerTaxon::Taxon(561),
+                    count: 2
+                },
+            ]
+        );
+        assert!(parse_lca_map(b"").unwrap().hits().is_empty());
+        assert!(parse_lca_map(b"562").is_err());
+        assert!(parse_lca_map(b"562:x").is_err());
+        assert!(parse_lca_map(b"abc:1").is_err());
+    }
+
+    #[test]
+    fn test_parse_lca_map_paired() {
+        let lca_map = parse_lca_map(b"562:13 A:4 |:| 0:1 561:2").unwrap();
+        assert!(lca_map.is_paired());
+        assert_eq!(lca_map.hits().len(), 4);
+        let mates: Vec<&[KmerHit]> = lca_map.mates().collect();
+        assert_eq!(mates.len(), 2);
+        assert_eq!(mates[0].len(), 2);
+        assert_eq!(mates[1].len(), 2);
+        assert_eq!(mates[1][0].taxon, KmerTaxon::Taxon(0));
+
+        // a mate too short to contain any k-mers
+        let lca_map = parse_lca_map(b"562:13 |:| ").unwrap();
+        let mates: Vec<&[KmerHit]> = lca_map.mates().collect();
+        assert_eq!(mates.len(), 2);
+        assert!(mates[1].is_empty());
+
+        assert!(parse_lca_map(b"562:1 |:| 562:1 |:| 562:1").is_err());
+    }
+
+    #[test]
+    fn test_process_kraken_output_line_paired() {
+        let line = "C\tread_123\t562\t150|148\t562:10 0:5 |:| 562:8";
+        let result = process_kraken_output_line(line.as_bytes()).unwrap();
+        assert_eq!(result.length, SequenceLength::Paired(150, 148));
+        assert_eq!(result.read_id, b"read_123");
+        let line = "C\tread_123\t562\t150\t562:10";
+        let result = process_kraken_output_line(line.as_bytes()).unwrap();
+        assert_eq!(result.length, SequenceLength::Single(150));
+        for length in ["150|abc", "150|", "|148", "abc", "150|148|2", ""] {
+            let line = format!("C\tread_123\t562\t{length}\t562:10");
+            assert!(
+                process_kraken_output_line(line.as_bytes()).is_err(),
+                "{length}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_process_kraken_output_length_error_line_number() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("kraken_output.txt");
+        File::create(&file_path)
+            .unwrap()
+            .write_all(b"C\tread_1\t1337\t150|148\t1337:10\nC\tread_2\t1337\t150|x\t1337:10\n")
+            .unwrap();
+        let err =
+            process_kraken_output(&file_path, false, &[1337], &ReadFilters::default(), 1, None)
+                .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Failed to parse kraken output at line 2: C\tread_2\t1337\t150|x\t1337:10"
+        );
+        assert_eq!(
+            err.root_cause().to_string(),
+            "Error parsing sequence length: '150|x'"
+        );
     }
 
     #[test]
@@ -392,8 +1291,15 @@ mod tests {
         let mut file = File::create(&file_path).unwrap();
         file.write_all(test_data.as_bytes()).unwrap();
         let taxon_ids_to_save = vec![1337];
-        let ProcessedKrakenOutput { reads_to_save, .. } =
-            process_kraken_output(&file_path, false, &taxon_ids_to_save).unwrap();
+        let ProcessedKrakenOutput { reads_to_save, .. } = process_kraken_output(
+            &file_path,
+            false,
+            &taxon_ids_to_save,
+            &ReadFilters::default(),
+            1,
+            None,
+        )
+        .unwrap();
         assert_eq!(reads_to_save.len(), 2);
         assert!(reads_to_save.contains(b"read_1".as_slice()));
         assert!(reads_to_save.contains(b"read_3".as_slice()));
@@ -413,8 +1319,15 @@ mod tests {
         let mut file = File::create(&file_path).unwrap();
         file.write_all(test_data.as_bytes()).unwrap();
         let taxon_ids_to_save = vec![1337, 0];
-        let ProcessedKrakenOutput { reads_to_save, .. } =
-            process_kraken_output(&file_path, false, &taxon_ids_to_save).unwrap();
+        let ProcessedKrakenOutput { reads_to_save, .. } = process_kraken_output(
+            &file_path,
+            false,
+            &taxon_ids_to_save,
+            &ReadFilters::default(),
+            1,
+            None,
+        )
+        .unwrap();
         assert_eq!(reads_to_save.len(), 3);
         assert!(reads_to_save.contains(b"read_1".as_slice()));
         assert!(reads_to_save.contains(b"read_3".as_slice()));
@@ -434,8 +1347,15 @@ mod tests {
         let mut file = File::create(&file_path).unwrap();
         file.write_all(test_data.as_bytes()).unwrap();
         let taxon_ids_to_save = vec![1337];
-        let ProcessedKrakenOutput { reads_to_save, .. } =
-            process_kraken_output(&file_path, true, &taxon_ids_to_save).unwrap();
+        let ProcessedKrakenOutput { reads_to_save, .. } = process_kraken_output(
+            &file_path,
+            true,
+            &taxon_ids_to_save,
+            &ReadFilters::default(),
+            1,
+            None,
+        )
+        .unwrap();
         assert_eq!(reads_to_save.len(), 2);
         assert!(!reads_to_save.contains(b"read_1".as_slice()));
         assert!(!reads_to_save.contains(b"read_3".as_slice()));
@@ -443,6 +1363,120 @@ mod tests {
         assert!(reads_to_save.contains(b"read_4".as_slice()));
     }
 
+    #[test]
+    fn test_process_kraken_output_exclude_mode_stores_smaller_side() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("kraken_output.txt");
+        let test_data = "C\tread_1\t9606\t150\t9606:10
+C\tread_2\t2\t150\t2:10
+C\tread_3\t562\t150\t562:10
+U\tread_4\t0\t150\t0:10";
+        let mut file = File::create(&file_path).unwrap();
+        file.write_all(test_data.as_bytes()).unwrap();
+        for threads in [1, 2] {
+            let ProcessedKrakenOutput { reads_to_save, .. } = process_kraken_output(
+                &file_path,
+                true,
+                &[9606],
+                &ReadFilters::default(),
+                threads,
+                None,
+            )
+            .unwrap();
+            assert!(reads_to_save.is_inverted());
+            assert_eq!(reads_to_save.stored(), 1);
+            assert_eq!(reads_to_save.len(), 3);
+            assert!(!reads_to_save.contains(b"read_1"));
+            assert!(reads_to_save.contains(b"read_2"));
+            assert!(reads_to_save.contains(b"read_3"));
+            assert!(reads_to_save.contains(b"read_4"));
+        }
+    }
+
+    #[test]
+    fn test_process_kraken_output_spills_over_max_memory() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("kraken_output.txt");
+        let mut test_data = String::new();
+        for read in 0..500 {
+            let taxon_id = if read % 2 == 0 { 562 } else { 2 };
+            test_data.push_str(&format!("C\tread_{read}\t{taxon_id}\t150\t{taxon_id}:10\n"));
+        }
+        File::create(&file_path)
+            .unwrap()
+            .write_all(test_data.as_bytes())
+            .unwrap();
+        for threads in [1, 3] {
+            let ProcessedKrakenOutput { reads_to_save, .. } = process_kraken_output(
+                &file_path,
+                false,
+                &[562],
+                &ReadFilters::default(),
+                threads,
+                Some(2048),
+            )
+            .unwrap();
+            assert!(reads_to_save.is_spilled());
+            assert!(reads_to_save.passes() > 1);
+            assert_eq!(reads_to_save.len(), 250);
+            let mut found = 0;
+            reads_to_save
+                .for_each_pass(|pass| {
+                    for read in 0..500 {
+                        if pass.contains(format!("read_{read}").as_bytes()) {
+                            assert_eq!(read % 2, 0);
+                            found += 1;
+                        }
+                    }
+                    Ok(())
+                })
+                .unwrap();
+            assert_eq!(found, 250);
+        }
+
+        let ProcessedKrakenOutput { reads_to_save, .. } = process_kraken_output(
+            &file_path,
+            false,
+            &[562],
+            &ReadFilters::default(),
+            1,
+            Some(1 << 30),
+        )
+        .unwrap();
+        assert!(!reads_to_save.is_spilled());
+        assert_eq!(reads_to_save.passes(), 1);
+    }
+
+    #[test]
+    fn test_process_kraken_output_strict_exclusion() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("kraken_output.txt");
+        let test_data = "\
+        C\tread_1\t9606\t150\t9606:20
+        C\tread_2\t1\t150\t9606:8 1:2 0:10
+        U\tread_3\t0\t150\t9606:2 0:18
+        C\tread_4\t2\t150\t2:20";
+        let mut file = File::create(&file_path).unwrap();
+        file.write_all(test_data.as_bytes()).unwrap();
+        let filters = ReadFilters {
+            strict_exclusion: Some(StrictExclusion {
+                min_kmers: None,
+                min_run: Some(5),
+            }),
+            ..Default::default()
+        };
+        let ProcessedKrakenOutput {
+            reads_to_save,
+            filter_stats,
+            ..
+        } = process_kraken_output(&file_path, true, &[9606], &filters, 1, None).unwrap();
+        assert_eq!(reads_to_save.len(), 2);
+        assert!(reads_to_save.contains(b"read_3".as_slice()));
+        assert!(reads_to_save.contains(b"read_4".as_slice()));
+        assert_eq!(filter_stats.reads_excluded_by_call, 1);
+        assert_eq!(filter_stats.reads_excluded_by_kmers, 1);
+    }
+
     #[test]
     fn test_taxon_counts_include_mode() {
         let dir = tempdir().unwrap();
@@ -457,7 +1491,15 @@ mod tests {
         let taxon_ids_to_save = vec![1337, 2];
         let ProcessedKrakenOutput {
             reads_per_taxon, ..
-        } = process_kraken_output(&file_path, false, &taxon_ids_to_save).unwrap();
+        } = process_kraken_output(
+            &file_path,
+            false,
+            &taxon_ids_to_save,
+            &ReadFilters::default(),
+            1,
+            None,
+        )
+        .unwrap();
         assert_eq!(reads_per_taxon.len(), 2);
         assert_eq!(*reads_per_taxon.get(&1337).unwrap(), 2);
         assert_eq!(*reads_per_taxon.get(&2).unwrap(), 1);
@@ -480,7 +1522,15 @@ mod tests {
         let taxon_ids_to_save = vec![1337, 2];
         let ProcessedKrakenOutput {
             reads_per_taxon, ..
-        } = process_kraken_output(&file_path, true, &taxon_ids_to_save).unwrap();
+        } = process_kraken_output(
+            &file_path,
+            true,
+            &taxon_ids_to_save,
+            &ReadFilters::default(),
+            1,
+            None,
+        )
+        .unwrap();
         assert_eq!(reads_per_taxon.len(), 2);
         assert_eq!(*reads_per_taxon.get(&1).unwrap(), 2);
         assert_eq!(*reads_per_taxon.get(&5).unwrap(), 1);
@@ -498,17 +1548,25 @@ mod tests {
         let mut file = File::create(&file_path).unwrap();
         file.write_all(test_data.as_bytes()).unwrap();
         let ProcessedKrakenOutput { reads_to_save, .. } =
-            process_kraken_output(&file_path, false, &[]).unwrap();
+            process_kraken_output(&file_path, false, &[], &ReadFilters::default(), 1, None)
+                .unwrap();
         assert_eq!(reads_to_save.len(), 0);
         let ProcessedKrakenOutput { reads_to_save, .. } =
-            process_kraken_output(&file_path, true, &[]).unwrap();
+            process_kraken_output(&file_path, true, &[], &ReadFilters::default(), 1, None).unwrap();
         assert_eq!(reads_to_save.len(), 2);
     }
 
     #[test]
     fn test_process_kraken_output_file_not_found() {
         let nonexistent_path = PathBuf::from("nonexistent_file.txt");
-        let result = process_kraken_output(&nonexistent_path, false, &[1337]);
+        let result = process_kraken_output(
+            &nonexistent_path,
+            false,
+            &[1337],
+            &ReadFilters::default(),
+            1,
+            None,
+        );
         assert!(result.is_err());
     }
 
@@ -522,7 +1580,8 @@ mod tests {
         C\tread_3\t1337\t150\t0:1 1:10";
         let mut file = File::create(&file_path).unwrap();
         file.write_all(test_data.as_bytes()).unwrap();
-        let result = process_kraken_output(&file_path, false, &[1337]);
+        let result =
+            process_kraken_output(&file_path, false, &[1337], &ReadFilters::default(), 1, None);
         assert!(result.is_err());
     }
 
@@ -556,6 +1615,22 @@ mod tests {
         assert_eq!(result.name, "Bacteria");
     }
 
+    #[test]
+    fn test_rank_order() {
+        let rank = |code: &str| code.parse::<Rank>().unwrap();
+        assert!(rank("R") < rank("D"));
+        assert!(rank("G") < rank("G1"));
+        assert!(rank("G1") < rank("G2"));
+        assert!(rank("G2") < rank("S"));
+        assert!(rank("S") < rank("S1"));
+        assert_eq!(rank("s1"), rank("S1"));
+        assert_eq!(rank("S1").to_string(), "S1");
+        assert!("U".parse::<Rank>().is_err());
+        assert!("X".parse::<Rank>().is_err());
+        assert!("G1a".parse::<Rank>().is_err());
+        assert!("".parse::<Rank>().is_err());
+    }
+
     #[test]
     fn test_process_kraken_report_line_missing_fields() {
         let line = "";
@@ -650,8 +1725,227 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_process_kraken_output_non_utf8_read_id() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("kraken_output.txt");
+        let mut file = File::create(&file_path).unwrap();
+        file.write_all(b"C\tread_\xff\t1337\t150\t1337:10\r\nC\tread_2\t2\t150\t2:10\r\n")
+            .unwrap();
+        let ProcessedKrakenOutput { reads_to_save, .. } =
+            process_kraken_output(&file_path, false, &[1337], &ReadFilters::default(), 1, None)
+                .unwrap();
+        assert_eq!(reads_to_save.len(), 1);
+        assert!(reads_to_save.contains(b"read_\xff".as_slice()));
+    }
+
+    #[test]
+    fn test_process_kraken_output_error_line_number() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("kraken_output.txt");
+        let mut file = File::create(&file_path).unwrap();
+        file.write_all(b"C\tread_1\t1337\t150\t1337:10\nC\tread_2\tabc\t150\t2:10\n")
+            .unwrap();
+        let err =
+            process_kraken_output(&file_path, false, &[1337], &ReadFilters::default(), 1, None)
+                .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Failed to parse kraken output at line 2: C\tread_2\tabc\t150\t2:10"
+        );
+    }
+
+    fn chunked_parser(filters: &ReadFilters, chunk_size: usize) -> KrakenOutputParser<'_> {
+        KrakenOutputParser {
+            exclude: false,
+            taxon_ids_to_save: [562, 0].into_iter().collect(),
+            filters,
+            chunk_size,
+            max_memory: None,
+            spill: Mutex::new(None),
+        }
+    }
+
+    #[test]
+    fn test_process_kraken_output_chunks_match_lines() {
+        let mut test_data = String::new();
+        for read in 0..1000 {
+            let taxon_id = [562, 590, 0][read % 3];
+            test_data.push_str(&format!(
+                "C\tread_{read}\t{taxon_id}\t150\t{taxon_id}:{} A:{}\n",
+                read % 17,
+                read % 5
+            ));
+        }
+        let filters = ReadFilters {
+            max_ambiguous_fraction: Some(0.5),
+            ..Default::default()
+        };
+        let mut sequential = ProcessedKrakenOutput::default();
+        chunked_parser(&filters, CHUNK_SIZE)
+            .process_lines(&mut test_data.as_bytes(), &mut sequential, 1, usize::MAX)
+            .unwrap();
+        // chunks much smaller than a line still end on a line boundary
+        for chunk_size in [1, 100, 4096] {
+            let parallel = chunked_parser(&filters, chunk_size)
+                .process_chunks(test_data.as_bytes(), 1, 4, false)
+                .unwrap();
+            assert_eq!(parallel.reads_to_save, sequential.reads_to_save);
+            assert_eq!(parallel.reads_per_taxon, sequential.reads_per_taxon);
+            assert_eq!(
+                parallel.filter_stats.reads_above_ambiguous_fraction,
+                sequential.filter_stats.reads_above_ambiguous_fraction
+            );
+            // the fractions are summed in a different order, so the means can differ by rounding
+            let parallel = parallel.filter_stats.ambiguous_fractions.summary();
+            let sequential = sequential.filter_stats.ambiguous_fractions.summary();
+            assert_eq!(parallel.histogram, sequential.histogram);
+            assert!((parallel.mean.unwrap() - sequential.mean.unwrap()).abs() < 1e-9);
+        }
+        assert!(sequential.filter_stats.reads_above_ambiguous_fraction > 0);
+    }
+
+    #[test]
+    fn test_process_kraken_output_chunks_error_line_number() {
+        let test_data = "C\tread_1\t562\t150\t562:10\r\nC\tread_2\t562\t150\t562:10\r\nC\tread_3\tabc\t150\t2:10\r\n";
+        let filters = ReadFilters::default();
+        let err = chunked_parser(&filters, 10)
+            .process_chunks(test_data.as_bytes(), 1, 2, false)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Failed to parse kraken output at line 3: C\tread_3\tabc\t150\t2:10"
+        );
+    }
+
+    #[test]
+    fn test_process_kraken_output_chunks_first_error() {
+        let mut test_data = String::new();
+        for read in 1..=200 {
+            let taxon_id = if read % 50 == 0 { "abc" } else { "562" };
+            test_data.push_str(&format!("C\tread_{read}\t{taxon_id}\t150\t562:10\n"));
+        }
+        let filters = ReadFilters::default();
+        // later chunks fail too, and may be picked up by a worker before the first bad one
+        for _ in 0..20 {
+            let err = chunked_parser(&filters, 100)
+                .process_chunks(test_data.as_bytes(), 1, 4, false)
+                .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "Failed to parse kraken output at line 50: C\tread_50\tabc\t150\t562:10"
+            );
+        }
+    }
+
+    #[test]
+    fn test_processed_kraken_output_merge() {
+        let mut first = ProcessedKrakenOutput::default();
+        first.reads_to_save.keep(b"read_1");
+        first.reads_per_taxon.insert(562, 1);
+        first.filter_stats.reads_below_confidence = 2;
+        let mut second = ProcessedKrakenOutput::default();
+        second.reads_to_save.keep(b"read_2");
+        second.reads_to_save.keep(b"read_3");
+        second.reads_per_taxon.insert(562, 1);
+        second.reads_per_taxon.insert(590, 1);
+        second.taxon_names.insert(590, "Salmonella".to_string());
+        second.filter_stats.reads_below_confidence = 3;
+        first.merge(second);
+        assert_eq!(first.reads_to_save.len(), 3);
+        assert_eq!(first.reads_per_taxon[&562], 2);
+        assert_eq!(first.reads_per_taxon[&590], 1);
+        assert_eq!(first.taxon_names[&590], "Salmonella");
+        assert_eq!(first.filter_stats.reads_below_confidence, 5);
+    }
+
+    #[test]
+    fn test_parse_numbers() {
+        assert_eq!(parse_u32(b"150"), Some(150));
+        assert_eq!(parse_u32(b"4294967295"), Some(u32::MAX));
+        assert_eq!(parse_u32(b"4294967296"), None);
+        assert_eq!(parse_u32(b""), None);
+        assert_eq!(parse_u32(b"1a"), None);
+        assert_eq!(parse_i32(b"562"), Some(562));
+        assert_eq!(parse_i32(b"-1"), Some(-1));
+        assert_eq!(parse_i32(b"2147483648"), None);
+        assert_eq!(parse_i32(b"-"), None);
+    }
+
+    #[test]
+    fn test_process_kraken_output_compressed() {
+        let dir = tempdir().unwrap();
+        let test_data = "C\tread_1\t1337\t150\t0:1 1:10
+C\tread_2\t2\t150\t0:1 1:10
+C\tread_3\t1337\t150\t0:1 1:10";
+        for (file_name, format) in [
+            ("kraken_output.txt.gz", niffler::Format::Gzip),
+            ("kraken_output.txt.bz2", niffler::Format::Bzip),
+        ] {
+            let file_path = dir.path().join(file_name);
+            let mut writer = niffler::get_writer(
+                Box::new(File::create(&file_path).unwrap()),
+                format,
+                niffler::Level::One,
+            )
+            .unwrap();
+            writer.write_all(test_data.as_bytes()).unwrap();
+            drop(writer);
+            let ProcessedKrakenOutput { reads_to_save, .. } =
+                process_kraken_output(&file_path, false, &[1337], &ReadFilters::default(), 1, None)
+                    .unwrap();
+            assert_eq!(reads_to_save.len(), 2);
+            assert!(reads_to_save.contains(b"read_1".as_slice()));
+            assert!(reads_to_save.contains(b"read_3".as_slice()));
+        }
+    }
+
+    #[test]
+    fn test_process_kraken_output_short_file() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("kraken_output.txt");
+        File::create(&file_path).unwrap();
+        let ProcessedKrakenOutput { reads_to_save, .. } =
+            process_kraken_output(&file_path, false, &[1337], &ReadFilters::default(), 1, None)
+                .unwrap();
+        assert!(reads_to_save.is_empty());
+
+        // too short to detect compression, but still parsed rather than treated as empty
+        File::create(&file_path)
+            .unwrap()
+            .write_all(b"xx\n")
+            .unwrap();
+        assert!(
+            process_kraken_output(&file_path, false, &[1337], &ReadFilters::default(), 1, None)
+                .is_err()
+        );
+    }
+
     // report parsing tests
 
+    #[test]
+    fn test_build_tree_from_kraken_report_gzip() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("kraken_report.txt.gz");
+        let test_data = "78.64\t2745487\t1646\tR\t1\troot
+78.21\t2730479\t8458\tD\t2\t  Bacteria
+16.50\t576156\t22486\tG\t1386\t    Bacillus";
+        let mut writer = niffler::get_writer(
+            Box::new(File::create(&file_path).unwrap()),
+            niffler::Format::Gzip,
+            niffler::Level::One,
+        )
+        .unwrap();
+        writer.write_all(test_data.as_bytes()).unwrap();
+        drop(writer);
+        let ProcessedKrakenTree {
+            nodes, taxon_map, ..
+        } = build_tree_from_kraken_report(&[1386], &file_path, true).unwrap();
+        assert_eq!(nodes.len(), 3);
+        assert_eq!(taxon_map[&1386], 2);
+        assert_eq!(nodes[2].parent, Some(1));
+    }
+
     #[test]
     fn test_build_tree_from_kraken_report_valid_with_unclassified() {
         let dir = tempdir().unwrap();
@@ -881,16 +2175,74 @@ mod tests {
         taxon_map.insert(10, 1);
         taxon_map.insert(20, 2);
         taxon_map.insert(30, 3);
-        let parents = extract_parents(&taxon_map, &tree, 30).unwrap();
+        let parents = extract_parents(&taxon_map, &tree, 30, None).unwrap();
         assert_eq!(parents, vec![30, 20, 10, 1]);
-        let parents = extract_parents(&taxon_map, &tree, 20).unwrap();
+        let parents = extract_parents(&taxon_map, &tree, 20, None).unwrap();
         assert_eq!(parents, vec![20, 10, 1]);
-        let parents = extract_parents(&taxon_map, &tree, 10).unwrap();
+        let parents = extract_parents(&taxon_map, &tree, 10, None).unwrap();
         assert_eq!(parents, vec![10, 1]);
-        let parents = extract_parents(&taxon_map, &tree, 1).unwrap();
+        let parents = extract_parents(&taxon_map, &tree, 1, None).unwrap();
         assert_eq!(parents, vec![1]);
     }
 
+    #[test]
+    fn test_extract_parents_up_to() {
+        let ranks = ["R", "F", "G", "G1", "S"];
+        let mut tree: Vec<Tree> = ranks
+            .iter()
+            .enumerate()
+            .map(|(index, rank)| Tree {
+                rank: rank.parse().ok(),
+                ..Tree::new(index as i32 * 10, index, index.checked_sub(1))
+            })
+            .collect();
+        for index in 1..tree.len() {
+            tree[index - 1].children.push(index);
+        }
+        let taxon_map: FxHashMap<i32, usize> = (0..tree.len())
+            .map(|index| (index as i32 * 10, index))
+            .collect();
+        let up_to = |limit: &str| Some(limit.parse::<ParentLimit>().unwrap());
+
+        let parents = extract_parents(&taxon_map, &tree, 40, up_to("G")).unwrap();
+        assert_eq!(parents, vec![40, 30, 20]);
+        let parents = extract_parents(&taxon_map, &tree, 40, up_to("g1")).unwrap();
+        assert_eq!(parents, vec![40, 30]);
+        // no ancestor at the rank, so the climb stops below the first rank above it
+        let parents = extract_parents(&taxon_map, &tree, 40, up_to("O")).unwrap();
+        assert_eq!(parents, vec![40, 30, 20, 10]);
+        let parents = extract_parents(&taxon_map, &tree, 20, up_to("S")).unwrap();
+        assert_eq!(parents, vec![20]);
+
+        let parents = extract_parents(&taxon_map, &tree, 40, up_to("10")).unwrap();
+        assert_eq!(parents, vec![40, 30, 20, 10]);
+        let parents = extract_parents(&taxon_map, &tree, 40, up_to("40")).unwrap();
+        assert_eq!(parents, vec![40]);
+        assert!(extract_parents(&taxon_map, &tree, 20, up_to("30")).is_err());
+        assert!("X1".parse::<ParentLimit>().is_err());
+    }
+
+    #[test]
+    fn test_find_ancestor_at_rank() {
+        let ranks = ["R", "F", "G", "S", "S1"];
+        let tree: Vec<Tree> = ranks
+            .iter()
+            .enumerate()
+            .map(|(index, rank)| Tree {
+                rank: rank.parse().ok(),
+                ..Tree::new(index as i32, index, index.checked_sub(1))
+            })
+            .collect();
+        let rank = |code: &str| code.parse::<Rank>().unwrap();
+
+        assert_eq!(find_ancestor_at_rank(&tree, 4, rank("G")), Some(2));
+        assert_eq!(find_ancestor_at_rank(&tree, 4, rank("S1")), Some(4));
+        assert_eq!(find_ancestor_at_rank(&tree, 3, rank("F")), Some(1));
+        // no ancestor at the rank, or the taxon is above it
+        assert_eq!(find_ancestor_at_rank(&tree, 4, rank("O")), None);
+        assert_eq!(find_ancestor_at_rank(&tree, 2, rank("S")), None);
+    }
+
     #[test]
     fn test_extract_children_valid() {
         let mut nodes = vec![
@@ -912,4 +2264,57 @@ mod tests {
         extract_children(&nodes, &mut result, 3).unwrap();
         assert_eq!(result, vec![30]);
     }
+
+    #[test]
+    fn test_ordered_kraken_output_reads_lines_as_they_arrive() {
+        let (pipe_reader, mut pipe_writer) = io::pipe().unwrap();
+        let (consumed_tx, consumed_rx) = channel::bounded::<()>(0);
+        // stands in for Kraken2, only writing each line once the previous one has been consumed
+        let kraken2 = std::thread::spawn(move || {
+            for line in [
+                "C\tread_1\t1337\t150\t1337:116\n",
+                "C\tread_2\t2\t150\t2:116\n",
+                "C\tread_3\t1337\t150\t1337:116\n",
+            ] {
+                pipe_writer.write_all(line.as_bytes()).unwrap();
+                if consumed_rx
+                    .recv_timeout(std::time::Duration::from_secs(10))
+                    .is_err()
+                {
+                    return false;
+                }
+            }
+            true
+        });
+
+        let filters = ReadFilters::default();
+        let reader = decompressed_reader(Box::new(pipe_reader)).unwrap();
+        let mut kraken = OrderedKrakenOutput::new(reader, false, &[1337], &filters);
+        let mut reads = Vec::new();
+        while let Some(read) = kraken.next_read().unwrap() {
+            reads.push((read.read_id.to_vec(), read.line_number, read.selected));
+            consumed_tx.send(()).unwrap();
+        }
+
+        assert!(kraken2.join().unwrap());
+        assert_eq!(
+            reads,
+            vec![
+                (b"read_1".to_vec(), 1, true),
+                (b"read_2".to_vec(), 2, false),
+                (b"read_3".to_vec(), 3, true),
+            ]
+        );
+        assert_eq!(kraken.finish().reads_per_taxon.get(&1337), Some(&2));
+    }
+
+    #[test]
+    fn test_is_kraken_pipe() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("kraken_output.txt");
+        File::create(&file_path).unwrap();
+
+        assert!(is_kraken_pipe(Path::new("-")));
+        assert!(!is_kraken_pipe(&file_path));
+    }
 }
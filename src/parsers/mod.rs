@@ -1,2 +1,23 @@
 pub mod fastx;
 pub mod kraken;
+
+use std::io::{self, BufRead, BufReader, Read};
+
+use color_eyre::eyre::{Context, Result};
+
+/// Wraps a stream in a buffered reader, decompressing it if it starts with a gzip or bzip2 header. Only the first five
+/// bytes are read to detect the format, so lines from a pipe are consumed as they arrive.
+pub fn decompressed_reader(mut stream: Box<dyn Read + Send>) -> Result<Box<dyn BufRead + Send>> {
+    // niffler needs five bytes to detect the format, anything shorter is read as uncompressed text
+    let mut prefix = Vec::with_capacity(5);
+    (&mut stream)
+        .take(5)
+        .read_to_end(&mut prefix)
+        .wrap_err("Failed to read the start of the stream")?;
+    if prefix.len() < 5 {
+        return Ok(Box::new(io::Cursor::new(prefix)));
+    }
+    let (stream, _) = niffler::send::get_reader(Box::new(io::Cursor::new(prefix).chain(stream)))
+        .wrap_err("Failed to detect compression format")?;
+    Ok(Box::new(BufReader::new(stream)))
+}
@@ -0,0 +1,623 @@
+use std::{
+    collections::hash_map::Entry,
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    mem,
+    ops::Range,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use color_eyre::{
+    Result,
+    eyre::{Context, eyre},
+};
+use fxhash::{FxHashMap, FxHashSet};
+use tempfile::TempDir;
+
+// read IDs are packed into a u64 as a 40 bit arena offset and a 24 bit length
+const LENGTH_BITS: u32 = 24;
+const MAX_LENGTH: usize = (1 << LENGTH_BITS) - 1;
+const MAX_OFFSET: usize = (1 << (u64::BITS - LENGTH_BITS)) - 1;
+
+/// A compact set of read IDs.
+///
+/// The IDs are stored back to back in a single arena, indexed by a 64-bit hash of each ID. Lookups compare the
+/// stored bytes, so matching is exact: an ID whose hash collides with a different ID, which is vanishingly rare,
+/// is kept in a separate fallback set instead.
+#[derive(Debug, Clone, Default)]
+pub struct ReadIdSet {
+    arena: Vec<u8>,
+    // hash of the ID -> packed offset and length in the arena
+    index: FxHashMap<u64, u64>,
+    collisions: FxHashSet<Box<[u8]>>,
+}
+
+impl ReadIdSet {
+    /// Adds a read ID, returning false if it was already present.
+    pub fn insert(&mut self, read_id: &[u8]) -> bool {
+        match self.index.entry(fxhash::hash64(read_id)) {
+            Entry::Vacant(entry) => match pack(self.arena.len(), read_id.len()) {
+                Some(packed) => {
+                    entry.insert(packed);
+                    self.arena.extend_from_slice(read_id);
+                    true
+                }
+                // IDs that cannot be packed are rare enough to store individually
+                None => self.collisions.insert(read_id.into()),
+            },
+            Entry::Occupied(entry) => {
+                if unpack(&self.arena, *entry.get()) == read_id {
+                    false
+                } else {
+                    self.collisions.insert(read_id.into())
+                }
+            }
+        }
+    }
+
+    pub fn contains(&self, read_id: &[u8]) -> bool {
+        self.index
+            .get(&fxhash::hash64(read_id))
+            .is_some_and(|&packed| unpack(&self.arena, packed) == read_id)
+            || (!self.collisions.is_empty() && self.collisions.contains(read_id))
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len() + self.collisions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        self.index
+            .values()
+            .map(|&packed| unpack(&self.arena, packed))
+            .chain(self.collisions.iter().map(|read_id| &read_id[..]))
+    }
+
+    /// Adds every read ID of another set.
+    pub fn extend(&mut self, other: &ReadIdSet) {
+        for read_id in other.iter() {
+            self.insert(read_id);
+        }
+    }
+
+    /// Releases the spare capacity left over from building the set.
+    pub fn shrink_to_fit(&mut self) {
+        self.arena.shrink_to_fit();
+        self.index.shrink_to_fit();
+    }
+
+    /// An estimate of the heap memory used by the set, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.arena.capacity()
+            + self.index.capacity() * (2 * mem::size_of::<u64>() + 1)
+            + self
+                .collisions
+                .iter()
+                .map(|read_id| read_id.len() + mem::size_of::<Box<[u8]>>())
+                .sum::<usize>()
+    }
+}
+
+impl PartialEq for ReadIdSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && other.iter().all(|read_id| self.contains(read_id))
+    }
+}
+
+impl Eq for ReadIdSet {}
+
+impl<'a> FromIterator<&'a [u8]> for ReadIdSet {
+    fn from_iter<I: IntoIterator<Item = &'a [u8]>>(iter: I) -> Self {
+        let mut read_ids = Self::default();
+        for read_id in iter {
+            read_ids.insert(read_id);
+        }
+        read_ids
+    }
+}
+
+/// The reads selected for extraction.
+///
+/// Only one side of the selection is stored: the reads to keep or, when that is smaller, the reads to drop, with the
+/// membership test flipped. Until `choose_side` is called both sides are stored, so the smaller one can be chosen.
+///
+/// When the stored IDs outgrow the memory limit they are spilled to disk, and the input is then read in several passes,
+/// each against the IDs of a group of partitions loaded back into memory.
+#[derive(Debug, Clone, Default)]
+pub struct ReadSelection {
+    ids: ReadIdSet,
+    // the stored IDs are the reads to drop, rather than the reads to keep
+    inverted: bool,
+    // the reads dropped so far, while the side to store has not been chosen
+    dropped: Option<ReadIdSet>,
+    selected: usize,
+    // lines of the Kraken2 output decided, selected or not
+    records: usize,
+    spilled: Option<Arc<SpilledReadIds>>,
+    // the spilled partitions covered by this selection, when it is a single pass over spilled IDs
+    partitions: Option<Range<usize>>,
+}
+
+impl ReadSelection {
+    /// A selection storing both sides until `choose_side` is called.
+    pub fn undecided() -> Self {
+        Self {
+            dropped: Some(ReadIdSet::default()),
+            ..Default::default()
+        }
+    }
+
+    /// A selection storing the reads to drop if `inverted`, otherwise the reads to keep.
+    pub fn storing(inverted: bool) -> Self {
+        Self {
+            inverted,
+            ..Default::default()
+        }
+    }
+
+    pub fn keep(&mut self, read_id: &[u8]) {
+        self.selected += 1;
+        self.records += 1;
+        if !self.inverted {
+            self.ids.insert(read_id);
+        }
+    }
+
+    pub fn reject(&mut self, read_id: &[u8]) {
+        self.records += 1;
+        if self.inverted {
+            self.ids.insert(read_id);
+        } else if let Some(dropped) = &mut self.dropped {
+            dropped.insert(read_id);
+        }
+    }
+
+    /// Stops storing both sides, keeping whichever is smaller so far.
+    pub fn choose_side(&mut self) {
+        if let Some(dropped) = self.dropped.take()
+            && dropped.len() < self.ids.len()
+        {
+            self.ids = dropped;
+            self.inverted = true;
+        }
+    }
+
+    pub fn contains(&self, read_id: &[u8]) -> bool {
+        // reads of other partitions are decided in their own pass
+        if let Some(partitions) = &self.partitions
+            && !partitions.contains(&partition(read_id))
+        {
+            return false;
+        }
+        self.ids.contains(read_id) != self.inverted
+    }
+
+    /// The number of reads selected.
+    pub fn len(&self) -> usize {
+        self.selected
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.selected == 0
+    }
+
+    /// The number of Kraken2 records decided, whether selected or not.
+    pub fn records(&self) -> usize {
+        self.records
+    }
+
+    /// Returns true if the reads to drop are stored, rather than the reads to keep.
+    pub fn is_inverted(&self) -> bool {
+        self.inverted
+    }
+
+    /// The number of read IDs held in memory.
+    pub fn stored(&self) -> usize {
+        self.ids.len() + self.dropped.as_ref().map_or(0, ReadIdSet::len)
+    }
+
+    /// Adds the reads of another selection storing the same side.
+    pub fn merge(&mut self, mut other: ReadSelection) {
+        debug_assert_eq!(self.inverted, other.inverted);
+        // extend the larger set, so fewer read IDs are reinserted
+        if other.ids.len() > self.ids.len() {
+            std::mem::swap(&mut self.ids, &mut other.ids);
+        }
+        self.ids.extend(&other.ids);
+        self.selected += other.selected;
+        self.records += other.records;
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.ids.shrink_to_fit();
+    }
+
+    /// An estimate of the heap memory used by the stored IDs, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.ids.memory_usage() + self.dropped.as_ref().map_or(0, ReadIdSet::memory_usage)
+    }
+
+    /// Returns true if the side to store has been chosen, so the stored IDs can be spilled.
+    pub fn can_spill(&self) -> bool {
+        self.dropped.is_none()
+    }
+
+    /// Removes the stored IDs from memory, to be written to a `SpillWriter`.
+    pub fn take_ids(&mut self) -> ReadIdSet {
+        mem::take(&mut self.ids)
+    }
+
+    /// Replaces the stored IDs with IDs spilled to disk.
+    pub fn set_spilled(&mut self, spilled: SpilledReadIds) {
+        self.ids = ReadIdSet::default();
+        self.spilled = Some(Arc::new(spilled));
+    }
+
+    pub fn is_spilled(&self) -> bool {
+        self.spilled.is_some()
+    }
+
+    /// The number of passes over the input needed to check every read.
+    pub fn passes(&self) -> usize {
+        self.spilled
+            .as_ref()
+            .map_or(1, |spilled| spilled.passes.len())
+    }
+
+    /// Runs `pass` once for each pass over the input, with the part of the selection it covers, and returns the result
+    /// of the last pass. A selection held in memory needs a single pass.
+    pub fn for_each_pass<T>(&self, mut pass: impl FnMut(&ReadSelection) -> Result<T>) -> Result<T> {
+        let Some(spilled) = &self.spilled else {
+            return pass(self);
+        };
+        let mut result = None;
+        for partitions in &spilled.passes {
+            let selection = ReadSelection {
+                ids: spilled.load(partitions.clone())?,
+                inverted: self.inverted,
+                selected: self.selected,
+                records: self.records,
+                partitions: Some(partitions.clone()),
+                ..Default::default()
+            };
+            result = Some(pass(&selection)?);
+        }
+        result.ok_or_else(|| eyre!("No passes over the spilled read IDs"))
+    }
+}
+
+impl PartialEq for ReadSelection {
+    fn eq(&self, other: &Self) -> bool {
+        self.ids == other.ids
+            && self.inverted == other.inverted
+            && self.dropped == other.dropped
+            && self.selected == other.selected
+            && self.records == other.records
+    }
+}
+
+impl Eq for ReadSelection {}
+
+// spilled read IDs are split by hash into this many partitions, which are loaded back in groups that fit the memory
+// limit
+const SPILL_PARTITIONS: usize = 64;
+// rough index overhead of each read ID once loaded back into memory
+const INDEX_BYTES_PER_ID: usize = 40;
+
+fn partition(read_id: &[u8]) -> usize {
+    // the top bits, as the set itself indexes on the full hash
+    (fxhash::hash64(read_id) >> (u64::BITS - SPILL_PARTITIONS.trailing_zeros())) as usize
+}
+
+/// Writes read IDs to partition files in a temporary directory, once a selection outgrows the memory limit.
+#[derive(Debug)]
+pub struct SpillWriter {
+    dir: TempDir,
+    files: Vec<BufWriter<File>>,
+    // estimated memory needed to load each partition
+    partition_sizes: Vec<usize>,
+    ids: usize,
+}
+
+impl SpillWriter {
+    pub fn new() -> Result<Self> {
+        let dir = tempfile::Builder::new()
+            .prefix("kractor-spill-")
+            .tempdir()
+            .wrap_err("Failed to create a temporary directory for spilled read IDs")?;
+        let files = (0..SPILL_PARTITIONS)
+            .map(|partition| {
+                let path = partition_path(&dir, partition);
+                File::create(&path)
+                    .map(BufWriter::new)
+                    .wrap_err_with(|| format!("Failed to create spill file: {}", path.display()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            dir,
+            files,
+            partition_sizes: vec![0; SPILL_PARTITIONS],
+            ids: 0,
+        })
+    }
+
+    pub fn write(&mut self, read_ids: &ReadIdSet) -> Result<()> {
+        for read_id in read_ids.iter() {
+            let partition = partition(read_id);
+            let length = u32::try_from(read_id.len())
+                .map_err(|_| eyre!("Read ID too long to spill to disk"))?;
+            let file = &mut self.files[partition];
+            file.write_all(&length.to_le_bytes())
+                .and_then(|_| file.write_all(read_id))
+                .wrap_err("Failed to write spilled read IDs")?;
+            self.partition_sizes[partition] += read_id.len() + INDEX_BYTES_PER_ID;
+        }
+        self.ids += read_ids.len();
+        Ok(())
+    }
+
+    /// The number of read IDs spilled so far.
+    pub fn len(&self) -> usize {
+        self.ids
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids == 0
+    }
+
+    /// Flushes the partition files, and groups consecutive partitions into passes that each fit within
+    /// `memory_limit` bytes once loaded.
+    pub fn finish(self, memory_limit: usize) -> Result<SpilledReadIds> {
+        for file in self.files {
+            file.into_inner()
+                .map_err(|err| err.into_error())
+                .wrap_err("Failed to flush spilled read IDs")?;
+        }
+        let mut passes = Vec::new();
+        let mut start = 0;
+        let mut size = 0;
+        for (partition, &partition_size) in self.partition_sizes.iter().enumerate() {
+            if partition > start && size + partition_size > memory_limit {
+                passes.push(start..partition);
+                start = partition;
+                size = 0;
+            }
+            size += partition_size;
+        }
+        passes.push(start..SPILL_PARTITIONS);
+        Ok(SpilledReadIds {
+            dir: self.dir,
+            passes,
+        })
+    }
+}
+
+/// Read IDs spilled to disk, removed along with their temporary directory when dropped.
+#[derive(Debug)]
+pub struct SpilledReadIds {
+    dir: TempDir,
+    passes: Vec<Range<usize>>,
+}
+
+impl SpilledReadIds {
+    fn load(&self, partitions: Range<usize>) -> Result<ReadIdSet> {
+        let mut read_ids = ReadIdSet::default();
+        let mut read_id = Vec::new();
+        for partition in partitions {
+            let path = partition_path(&self.dir, partition);
+            let mut file = BufReader::new(
+                File::open(&path)
+                    .wrap_err_with(|| format!("Failed to open spill file: {}", path.display()))?,
+            );
+            let mut length = [0; 4];
+            loop {
+                match file.read_exact(&mut length) {
+                    Ok(()) => {}
+                    Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                    Err(err) => {
+                        return Err(err).wrap_err_with(|| {
+                            format!("Failed to read spill file: {}", path.display())
+                        });
+                    }
+                }
+                read_id.resize(u32::from_le_bytes(length) as usize, 0);
+                file.read_exact(&mut read_id)
+                    .wrap_err_with(|| format!("Failed to read spill file: {}", path.display()))?;
+                read_ids.insert(&read_id);
+            }
+        }
+        Ok(read_ids)
+    }
+}
+
+fn partition_path(dir: &TempDir, partition: usize) -> PathBuf {
+    dir.path().join(format!("partition_{partition}"))
+}
+
+fn pack(offset: usize, length: usize) -> Option<u64> {
+    (offset <= MAX_OFFSET && length <= MAX_LENGTH)
+        .then_some(((offset as u64) << LENGTH_BITS) | length as u64)
+}
+
+fn unpack(arena: &[u8], packed: u64) -> &[u8] {
+    let offset = (packed >> LENGTH_BITS) as usize;
+    let length = (packed & MAX_LENGTH as u64) as usize;
+    &arena[offset..offset + length]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_contains() {
+        let mut read_ids = ReadIdSet::default();
+        assert!(read_ids.is_empty());
+        assert!(read_ids.insert(b"read_1"));
+        assert!(read_ids.insert(b"read_2"));
+        assert!(!read_ids.insert(b"read_1"));
+        assert!(read_ids.insert(b""));
+        assert_eq!(read_ids.len(), 3);
+        assert!(read_ids.contains(b"read_1"));
+        assert!(read_ids.contains(b"read_2"));
+        assert!(read_ids.contains(b""));
+        assert!(!read_ids.contains(b"read_3"));
+        assert!(!read_ids.contains(b"read_"));
+    }
+
+    #[test]
+    fn test_hash_collision() {
+        let mut read_ids = ReadIdSet::default();
+        read_ids.insert(b"read_1");
+        // point the hash of read_2 at read_1, as if the two IDs collided
+        let packed = read_ids.index[&fxhash::hash64(b"read_1")];
+        read_ids.index.insert(fxhash::hash64(b"read_2"), packed);
+
+        assert!(!read_ids.contains(b"read_2"));
+        assert!(read_ids.insert(b"read_2"));
+        assert!(!read_ids.insert(b"read_2"));
+        assert!(read_ids.contains(b"read_2"));
+        assert_eq!(read_ids.collisions.len(), 1);
+    }
+
+    #[test]
+    fn test_extend_and_eq() {
+        let first: ReadIdSet = [b"read_1".as_slice(), b"read_2"].into_iter().collect();
+        let second: ReadIdSet = [b"read_2".as_slice(), b"read_3"].into_iter().collect();
+        let mut merged = first.clone();
+        merged.extend(&second);
+        merged.shrink_to_fit();
+        assert_eq!(merged.len(), 3);
+        assert_eq!(
+            merged,
+            [b"read_3".as_slice(), b"read_1", b"read_2"]
+                .into_iter()
+                .collect()
+        );
+        assert_ne!(merged, first);
+    }
+
+    #[test]
+    fn test_read_selection_keeps_smaller_side() {
+        let mut selection = ReadSelection::undecided();
+        selection.keep(b"read_1");
+        selection.reject(b"read_2");
+        selection.keep(b"read_3");
+        assert_eq!(selection.stored(), 3);
+        selection.choose_side();
+        assert!(selection.is_inverted());
+        assert_eq!(selection.stored(), 1);
+
+        // only the dropped reads are stored from now on
+        selection.keep(b"read_4");
+        selection.reject(b"read_5");
+        assert_eq!(selection.stored(), 2);
+        assert_eq!(selection.len(), 3);
+        assert_eq!(selection.records(), 5);
+        for read_id in [b"read_1", b"read_3", b"read_4"] {
+            assert!(selection.contains(read_id));
+        }
+        assert!(!selection.contains(b"read_2"));
+        assert!(!selection.contains(b"read_5"));
+    }
+
+    #[test]
+    fn test_read_selection_keeps_kept_side() {
+        let mut selection = ReadSelection::undecided();
+        selection.keep(b"read_1");
+        selection.reject(b"read_2");
+        selection.choose_side();
+        assert!(!selection.is_inverted());
+        selection.reject(b"read_3");
+        assert_eq!(selection.stored(), 1);
+        assert!(selection.contains(b"read_1"));
+        assert!(!selection.contains(b"read_2"));
+        assert!(!selection.contains(b"read_3"));
+
+        let mut other = ReadSelection::storing(false);
+        other.keep(b"read_4");
+        selection.merge(other);
+        assert_eq!(selection.len(), 2);
+        assert!(selection.contains(b"read_4"));
+    }
+
+    fn spilled_selection(inverted: bool, memory_limit: usize) -> ReadSelection {
+        let mut selection = ReadSelection::storing(inverted);
+        let mut writer = SpillWriter::new().unwrap();
+        for read in 0..100 {
+            let read_id = format!("read_{read}");
+            if inverted {
+                selection.reject(read_id.as_bytes());
+            } else {
+                selection.keep(read_id.as_bytes());
+            }
+            // spill in batches, as the parser does when it reaches the limit
+            if read % 30 == 0 {
+                writer.write(&selection.take_ids()).unwrap();
+            }
+        }
+        writer.write(&selection.take_ids()).unwrap();
+        assert_eq!(writer.len(), 100);
+        selection.set_spilled(writer.finish(memory_limit).unwrap());
+        selection
+    }
+
+    #[test]
+    fn test_spilled_selection_passes() {
+        let selection = spilled_selection(false, 500);
+        assert!(selection.is_spilled());
+        assert!(selection.passes() > 1);
+        let mut found = Vec::new();
+        let mut passes = 0;
+        selection
+            .for_each_pass(|pass| {
+                passes += 1;
+                for read in 0..120 {
+                    let read_id = format!("read_{read}");
+                    if pass.contains(read_id.as_bytes()) {
+                        found.push(read);
+                    }
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(passes, selection.passes());
+        // each read is found in exactly one pass
+        found.sort_unstable();
+        assert_eq!(found, (0..100).collect::<Vec<_>>());
+
+        // a large enough limit loads every partition at once
+        assert_eq!(spilled_selection(false, usize::MAX).passes(), 1);
+    }
+
+    #[test]
+    fn test_spilled_selection_inverted() {
+        let selection = spilled_selection(true, 500);
+        let mut kept = 0;
+        selection
+            .for_each_pass(|pass| {
+                for read in 0..120 {
+                    if pass.contains(format!("read_{read}").as_bytes()) {
+                        kept += 1;
+                    }
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(kept, 20);
+    }
+
+    #[test]
+    fn test_pack() {
+        let packed = pack(MAX_OFFSET, MAX_LENGTH).unwrap();
+        assert_eq!(packed >> LENGTH_BITS, MAX_OFFSET as u64);
+        assert_eq!(packed & MAX_LENGTH as u64, MAX_LENGTH as u64);
+        assert_eq!(pack(MAX_OFFSET + 1, 1), None);
+        assert_eq!(pack(0, MAX_LENGTH + 1), None);
+    }
+}